@@ -0,0 +1,351 @@
+use std::fmt::Write as _;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+use exercise::{Args, Cluster, Violation};
+
+const USAGE: &str = "
+Usage: campaign [--runs=<#>] [--concurrency=<#>] [exercise options]
+
+Options:
+    --runs=<#>         Number of runs [default: 12].
+    --concurrency=<#>  Most runs at the same time [default: 4].
+    --first-seed=<#>   Seed of the first run, later runs count up from it [default: random].
+    --topologies=<t>   Topologies to cycle through, like `1x3,3x3` for clusters x servers [default: 1x3].
+    --summary=<file>   Where to write the summary table [default: campaign_summary.txt].
+
+Every other option is passed on to each run, see the usage
+of `exercise`. The seed, topology and trace of each run are
+set by the campaign, so --seed, --clusters, --servers,
+--servers-per-cluster, --trace, --history, --replay,
+--shrink and --burn-in can't be passed.
+";
+
+// options that the campaign sets for each run, or that
+// would make runs overwrite each other's files
+const RESERVED: &[&str] = &[
+    "seed",
+    "clusters",
+    "servers",
+    "servers-per-cluster",
+    "trace",
+    "history",
+    "replay",
+    "shrink",
+    "burn-in",
+];
+
+#[derive(Debug, Clone, Copy)]
+struct Topology {
+    clusters: usize,
+    servers_per_cluster: usize,
+}
+
+impl std::str::FromStr for Topology {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Topology, String> {
+        let invalid = || format!("invalid topology {:?}, expected something like 3x3", s);
+        let parts: Vec<&str> = s.split('x').collect();
+        match parts.as_slice() {
+            [clusters, servers_per_cluster] => Ok(Topology {
+                clusters: clusters.parse().map_err(|_| invalid())?,
+                servers_per_cluster: servers_per_cluster.parse().map_err(|_| invalid())?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl std::fmt::Display for Topology {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{}", self.clusters, self.servers_per_cluster)
+    }
+}
+
+#[derive(Debug)]
+struct Options {
+    runs: usize,
+    concurrency: usize,
+    first_seed: u64,
+    topologies: Vec<Topology>,
+    summary: PathBuf,
+    // passed on to every run
+    passthrough: Vec<String>,
+}
+
+fn parse<'a, I, T>(mut iter: I) -> T
+where
+    I: Iterator<Item = &'a str>,
+    T: std::str::FromStr,
+    <T as std::str::FromStr>::Err: std::fmt::Debug,
+{
+    iter.next().expect(USAGE).parse().expect(USAGE)
+}
+
+impl Options {
+    fn parse() -> Options {
+        let mut options = Options {
+            runs: 12,
+            concurrency: 4,
+            first_seed: rand::random(),
+            topologies: vec![Topology {
+                clusters: 1,
+                servers_per_cluster: 3,
+            }],
+            summary: "campaign_summary.txt".into(),
+            passthrough: vec![],
+        };
+
+        for raw_arg in std::env::args().skip(1) {
+            let mut splits = raw_arg[2..].split('=');
+            match splits.next().unwrap() {
+                "runs" => options.runs = parse(&mut splits),
+                "concurrency" => options.concurrency = parse(&mut splits),
+                "first-seed" => options.first_seed = parse(&mut splits),
+                "topologies" => {
+                    let topologies: String = parse(&mut splits);
+                    options.topologies = topologies
+                        .split(',')
+                        .map(|t| t.parse().expect(USAGE))
+                        .collect();
+                }
+                "summary" => options.summary = parse(&mut splits),
+                name if RESERVED.contains(&name) => {
+                    panic!("{} is set by the campaign, {}", raw_arg, USAGE)
+                }
+                _ => options.passthrough.push(raw_arg),
+            }
+        }
+
+        assert!(options.concurrency > 0, "--concurrency must be positive");
+        assert!(!options.topologies.is_empty(), "no topologies, {}", USAGE);
+
+        options
+    }
+
+    fn seed(&self, run: usize) -> u64 {
+        self.first_seed.wrapping_add(run as u64)
+    }
+
+    fn topology(&self, run: usize) -> Topology {
+        self.topologies[run % self.topologies.len()]
+    }
+
+    /// The exercise options of a run.
+    fn run_args(&self, run: usize) -> Vec<String> {
+        let topology = self.topology(run);
+        self.passthrough
+            .iter()
+            .cloned()
+            .chain(vec![
+                format!("--seed={}", self.seed(run)),
+                format!("--clusters={}", topology.clusters),
+                format!("--servers-per-cluster={}", topology.servers_per_cluster),
+            ])
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+enum Outcome {
+    Passed,
+    Findings(usize),
+    Violation(Violation),
+    Panicked(String),
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Passed => write!(f, "passed"),
+            Outcome::Findings(n) => write!(f, "{} findings", n),
+            Outcome::Violation(violation) => {
+                write!(f, "{} violation ({})", violation.kind, violation.check)
+            }
+            Outcome::Panicked(message) => write!(f, "panicked: {}", message),
+        }
+    }
+}
+
+struct RunResult {
+    run: usize,
+    seed: u64,
+    topology: Topology,
+    outcome: Outcome,
+    // every finding of the run, even if it also failed otherwise
+    findings: Vec<String>,
+    elapsed: Duration,
+    trace: Option<PathBuf>,
+}
+
+fn run(options: &Options, run: usize) -> RunResult {
+    let start = Instant::now();
+    let mut trace = None;
+    let mut findings = vec![];
+
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+        let args = Args::parse_from(options.run_args(run));
+        let mut cluster = Cluster::start(args);
+        trace = cluster.trace().map(PathBuf::from);
+        let result = cluster.run();
+        findings = cluster
+            .findings()
+            .iter()
+            .map(|finding| format!("after {:?}: {}", finding.elapsed, finding.description))
+            .collect();
+        match result {
+            Err(violation) => Outcome::Violation(violation),
+            Ok(()) if findings.is_empty() => Outcome::Passed,
+            Ok(()) => Outcome::Findings(findings.len()),
+        }
+    }))
+    .unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Outcome::Panicked(message.lines().next().unwrap_or_default().to_string())
+    });
+
+    RunResult {
+        run,
+        seed: options.seed(run),
+        topology: options.topology(run),
+        outcome,
+        findings,
+        elapsed: start.elapsed(),
+        trace,
+    }
+}
+
+fn summary(results: &[RunResult]) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "{:<5} {:<20} {:<9} {:<10} outcome",
+        "run", "seed", "topology", "elapsed"
+    );
+    for result in results {
+        let _ = writeln!(
+            out,
+            "{:<5} {:<20} {:<9} {:<10} {}",
+            result.run,
+            result.seed,
+            result.topology.to_string(),
+            format!("{}s", result.elapsed.as_secs()),
+            result.outcome
+        );
+    }
+
+    let failed: Vec<&RunResult> = results
+        .iter()
+        .filter(|r| !matches!(r.outcome, Outcome::Passed))
+        .collect();
+
+    let _ = writeln!(out, "\n{} of {} runs failed", failed.len(), results.len());
+
+    for result in failed {
+        let _ = match &result.trace {
+            Some(trace) => writeln!(
+                out,
+                "\n    seed {} ({}): exercise --replay={}",
+                result.seed,
+                result.outcome,
+                trace.display()
+            ),
+            None => writeln!(
+                out,
+                "\n    seed {} ({}): no trace",
+                result.seed, result.outcome
+            ),
+        };
+
+        if let Outcome::Violation(violation) = &result.outcome {
+            indented(&mut out, &violation.description);
+        }
+        for finding in &result.findings {
+            indented(&mut out, &format!("finding {}", finding));
+        }
+    }
+
+    out
+}
+
+/// Writes every line of `text` below the run it belongs to,
+/// whatever indentation it had.
+fn indented(out: &mut String, text: &str) {
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let _ = writeln!(out, "        {}", line);
+    }
+}
+
+fn main() {
+    let options = Options::parse();
+
+    println!("starting campaign with options:");
+    println!("{:?}", options);
+
+    // fail on bad options before starting anything
+    for run in 0..options.runs.min(options.topologies.len()) {
+        Args::parse_from(options.run_args(run));
+    }
+
+    let options = Arc::new(options);
+    let next_run = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = mpsc::channel();
+
+    let workers: Vec<_> = (0..options.concurrency.min(options.runs))
+        .map(|_| {
+            let options = options.clone();
+            let next_run = next_run.clone();
+            let tx = tx.clone();
+            std::thread::spawn(move || loop {
+                let idx = next_run.fetch_add(1, SeqCst);
+                if idx >= options.runs {
+                    return;
+                }
+                let _ = tx.send(run(&options, idx));
+            })
+        })
+        .collect();
+    drop(tx);
+
+    let mut results = vec![];
+    for result in rx {
+        println!(
+            "run {} with seed {} ({}) finished after {:?}: {}",
+            result.run, result.seed, result.topology, result.elapsed, result.outcome
+        );
+        results.push(result);
+    }
+
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    results.sort_by_key(|r| r.run);
+
+    let summary = summary(&results);
+    println!("\n{}", summary);
+    std::fs::write(&options.summary, &summary).expect("unable to write summary");
+    println!("summary written to {:?}", options.summary);
+
+    if results
+        .iter()
+        .any(|r| matches!(r.outcome, Outcome::Violation(_) | Outcome::Panicked(_)))
+    {
+        std::process::exit(1);
+    }
+    if results
+        .iter()
+        .any(|r| matches!(r.outcome, Outcome::Findings(_)))
+    {
+        std::process::exit(2);
+    }
+}
@@ -0,0 +1,31 @@
+const USAGE: &str = "
+Usage: check <history.ndjson>
+
+Runs the durability and ordering checks of a live exercise
+run against a history file recorded with
+`exercise --history=<file>`. Availability and liveness
+deadlines, stream audits and server logs are only checked
+during live runs.
+";
+
+fn main() {
+    let path = std::env::args().nth(1).expect(USAGE);
+
+    match exercise::check_history(&path) {
+        Ok(None) => println!("no violations found in {}", path),
+        Ok(Some(violation)) => {
+            eprintln!(
+                "
+            {} violation ({}) found in {}.
+            {}
+            ",
+                violation.kind, violation.check, path, violation.description
+            );
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("unable to check {}: {}", path, e);
+            std::process::exit(3);
+        }
+    }
+}
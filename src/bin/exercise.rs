@@ -4,11 +4,33 @@ fn main() {
     println!("starting fault injector with arguments:");
     println!("{:?}", args);
 
-    let steps = if args.burn_in { u64::MAX } else { args.steps };
+    if args.shrink.is_some() {
+        match exercise::shrink(args) {
+            Ok(path) => println!(
+                "replay the shrunk schedule with --replay={}",
+                path.display()
+            ),
+            Err(e) => {
+                eprintln!("unable to shrink schedule: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
     let mut cluster = exercise::Cluster::start(args);
 
-    for _ in 0..steps {
-        cluster.step();
+    if cluster.run().is_err() {
+        // dropping the cluster kills its servers
+        drop(cluster);
+        std::process::exit(1);
+    }
+
+    if !cluster.findings().is_empty() {
+        eprintln!("run finished with {} findings:", cluster.findings().len());
+        for finding in cluster.findings() {
+            eprintln!("    after {:?}: {}", finding.elapsed, finding.description);
+        }
+        std::process::exit(2);
     }
 }
@@ -3,16 +3,24 @@ use std::convert::TryInto;
 use std::io;
 use std::mem;
 use std::sync::atomic::{AtomicU64, Ordering::SeqCst};
+use std::time::{Duration, Instant};
 
 use rand::seq::SliceRandom;
-use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand::{rngs::StdRng, SeedableRng};
 
 use nats::jetstream::{ConsumerConfig, RetentionPolicy, StreamConfig};
 
+use exercise::{check_ready, Op, Profile, Readiness};
+
 const STREAM: &str = "exercise_stream";
 
 const SERVERS: usize = 3;
 
+const CLIENT_PORT: u16 = 4222;
+
+// as configured in antithesis/server-common
+const HTTP_PORT: u16 = 8222;
+
 const USAGE: &str = "
 Usage: exercise [--path=</path/to/nats-server>]
 
@@ -21,6 +29,10 @@ Options:
     --steps=<#>     Number of steps to take [default: 10000].
     --replicas=<#>  Number of replicas for the JetStream test stream [default: 1].
     --burn-in       Ignore steps and run tests until we crash [default: unset].
+    --profile=<p>   Built-in profile name or profile file with op weights [default: publish 3, consume 7].
+    --weights=<w>   Override weights, like `publish:x2` [default: unset].
+
+Only the publish and consume weights of a profile are used.
 ";
 
 // generates unique (for this test run) ID
@@ -29,22 +41,46 @@ fn idgen() -> u64 {
     IDGEN.fetch_add(1, SeqCst)
 }
 
-fn nc(server_number: usize) -> nats::Connection {
+fn host(server_number: usize) -> String {
     assert!(
         server_number > 0 && server_number < 130,
         "invalid server number {}, must be > 0 and < 130",
         server_number
     );
-    loop {
-        // we add 1 to the server number IP because the network may have 10.20.20.1 already used
-        if let Ok(nc) = nats::connect(&format!("10.20.20.{}:4222", server_number + 1)) {
-            return nc;
-        } else {
-            std::thread::sleep(std::time::Duration::from_millis(10))
+    // we add 1 to the server number IP because the network may have 10.20.20.1 already used
+    format!("10.20.20.{}", server_number + 1)
+}
+
+/// Waits until every server accepts clients, is healthy with
+/// JetStream current, and routes to all of the others.
+fn await_ready() {
+    let start = Instant::now();
+    let readiness = Readiness {
+        routes: SERVERS - 1,
+        gateways: 0,
+        jetstream_current: true,
+    };
+
+    for server_number in 1..=SERVERS {
+        let mut last_problem = String::new();
+        while let Err(problem) =
+            check_ready(&host(server_number), CLIENT_PORT, HTTP_PORT, readiness)
+        {
+            if problem != last_problem {
+                println!("waiting for server {}: {}", server_number, problem);
+                last_problem = problem;
+            }
+            std::thread::sleep(Duration::from_millis(100));
         }
+        println!("server {} ready after {:?}", server_number, start.elapsed());
     }
 }
 
+fn nc(server_number: usize) -> nats::Connection {
+    nats::connect(&format!("{}:{}", host(server_number), CLIENT_PORT))
+        .expect("unable to connect to a ready server")
+}
+
 struct Cluster {
     clients: Vec<Consumer>,
     args: Args,
@@ -57,11 +93,11 @@ impl Cluster {
     fn start(args: Args) -> Cluster {
         let rng = SeedableRng::seed_from_u64(0);
 
+        await_ready();
+
         println!("creating testing stream {}", STREAM);
 
         loop {
-            std::thread::sleep(std::time::Duration::from_secs(1));
-
             let nc = nc(1);
 
             let _ = nc.delete_stream(STREAM);
@@ -76,6 +112,8 @@ impl Cluster {
             if ret.is_ok() {
                 break;
             }
+
+            std::thread::sleep(Duration::from_secs(1));
         }
 
         let clients: Vec<Consumer> = (1..=SERVERS)
@@ -108,7 +146,7 @@ impl Cluster {
 
         Cluster {
             clients,
-            rng: rng,
+            rng,
             args,
             durability_model: Default::default(),
             unvalidated_consumers: Default::default(),
@@ -116,10 +154,10 @@ impl Cluster {
     }
 
     fn step(&mut self) -> bool {
-        let progressed = match self.rng.gen_range(0..10) {
-            0..=2 => self.publish(),
-            3..=10 => self.consume(),
-            _ => unreachable!("impossible choice"),
+        let progressed = match self.args.profile.choose(&mut self.rng) {
+            Op::Publish => self.publish(),
+            Op::Consume => self.consume(),
+            _ => unreachable!("the profile only allows client operations"),
         };
 
         if progressed {
@@ -207,6 +245,7 @@ struct Args {
     steps: u64,
     num_replicas: usize,
     burn_in: bool,
+    profile: Profile,
     start_time: std::time::Instant,
 }
 
@@ -217,6 +256,7 @@ impl Default for Args {
             steps: 10000,
             num_replicas: 1,
             burn_in: false,
+            profile: Profile::from_weights(&[(Op::Publish, 3), (Op::Consume, 7)]),
             start_time: std::time::Instant::now(),
         }
     }
@@ -234,6 +274,7 @@ where
 impl Args {
     fn parse() -> Args {
         let mut args = Args::default();
+        let mut weights: Option<String> = None;
         for raw_arg in std::env::args().skip(1) {
             let mut splits = raw_arg[2..].split('=');
             match splits.next().unwrap() {
@@ -241,9 +282,20 @@ impl Args {
                 "steps" => args.steps = parse(&mut splits),
                 "replicas" => args.num_replicas = parse(&mut splits),
                 "burn-in" => args.burn_in = true,
+                "profile" => args.profile = parse(&mut splits),
+                "weights" => weights = Some(parse(&mut splits)),
                 other => panic!("unknown option: {}, {}", other, USAGE),
             }
         }
+        if let Some(weights) = weights {
+            args.profile.apply_overrides(&weights).expect(USAGE);
+        }
+        args.profile.restrict(&[Op::Publish, Op::Consume]);
+        assert!(
+            args.profile.total() > 0,
+            "publish and consume are both disabled, {}",
+            USAGE
+        );
         args
     }
 }
@@ -260,10 +312,6 @@ fn main() {
 
     println!("cluster ready for fault injection");
 
-    println!("workload and correctness assertions begin in 10 seconds");
-
-    std::thread::sleep(std::time::Duration::from_secs(10));
-
     println!("starting workload and correctness assertions now");
 
     let mut progressed_steps = 0;
@@ -0,0 +1,114 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// credentials that servers use to authenticate routes and gateways
+const ROUTE_USER: &str = "cu";
+const ROUTE_PASSWORD: &str = "cp";
+
+/// The config file of one server in the cluster. Ports
+/// in `routes` are dialed as given, so they may point at
+/// proxies rather than at the other servers directly.
+#[derive(Debug, Clone)]
+pub(crate) struct ServerConf {
+    pub name: String,
+    pub cluster_name: String,
+    pub cluster_port: u16,
+    // the address gossiped to other servers for implicit routes
+    pub advertise: u16,
+    pub routes: Vec<u16>,
+    // only set when the cluster is part of a supercluster
+    pub gateway: Option<GatewayConf>,
+}
+
+/// The gateway of a server to the other clusters of a
+/// supercluster.
+#[derive(Debug, Clone)]
+pub(crate) struct GatewayConf {
+    pub port: u16,
+    // the address gossiped to other clusters for implicit gateways
+    pub advertise: u16,
+    // the name of every other cluster, with the ports to dial it on
+    pub remotes: Vec<(String, Vec<u16>)>,
+}
+
+impl ServerConf {
+    /// Renders the config in the nats-server config format.
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+
+        // writing to a String can't fail
+        let _ = writeln!(out, "server_name = {:?}", self.name);
+        out.push_str(
+            "
+# keep lame duck mode short enough to be exercised
+lame_duck_duration = \"10s\"
+lame_duck_grace_period = \"2s\"
+",
+        );
+
+        let _ = writeln!(out, "\ncluster {{");
+        let _ = writeln!(out, "  name: {:?}", self.cluster_name);
+        let _ = writeln!(out, "  no_advertise: true");
+        let _ = writeln!(out, "  listen: 127.0.0.1:{}", self.cluster_port);
+        let _ = writeln!(out, "  advertise: \"127.0.0.1:{}\"", self.advertise);
+        let _ = writeln!(out, "  authorization {{");
+        let _ = writeln!(out, "    user: {}", ROUTE_USER);
+        let _ = writeln!(out, "    password: {}", ROUTE_PASSWORD);
+        let _ = writeln!(out, "    timeout: 0.5");
+        let _ = writeln!(out, "  }}");
+        let _ = writeln!(out, "  routes = [");
+        for port in &self.routes {
+            let _ = writeln!(
+                out,
+                "    nats-route://{}:{}@127.0.0.1:{}",
+                ROUTE_USER, ROUTE_PASSWORD, port
+            );
+        }
+        let _ = writeln!(out, "  ]");
+        let _ = writeln!(out, "}}");
+
+        if let Some(gateway) = &self.gateway {
+            let _ = writeln!(out, "\ngateway {{");
+            let _ = writeln!(out, "  name: {:?}", self.cluster_name);
+            let _ = writeln!(out, "  listen: 127.0.0.1:{}", gateway.port);
+            let _ = writeln!(out, "  advertise: \"127.0.0.1:{}\"", gateway.advertise);
+            let _ = writeln!(out, "  authorization {{");
+            let _ = writeln!(out, "    user: {}", ROUTE_USER);
+            let _ = writeln!(out, "    password: {}", ROUTE_PASSWORD);
+            let _ = writeln!(out, "    timeout: 0.5");
+            let _ = writeln!(out, "  }}");
+            let _ = writeln!(out, "  gateways = [");
+            for (name, ports) in &gateway.remotes {
+                let urls: Vec<String> = ports
+                    .iter()
+                    .map(|port| {
+                        format!(
+                            "\"nats://{}:{}@127.0.0.1:{}\"",
+                            ROUTE_USER, ROUTE_PASSWORD, port
+                        )
+                    })
+                    .collect();
+                let _ = writeln!(
+                    out,
+                    "    {{ name: {:?}, urls: [{}] }}",
+                    name,
+                    urls.join(", ")
+                );
+            }
+            let _ = writeln!(out, "  ]");
+            let _ = writeln!(out, "}}");
+        }
+
+        out
+    }
+
+    /// Writes the rendered config into `dir`, returning
+    /// the path of the file.
+    pub(crate) fn write(&self, dir: &Path) -> io::Result<PathBuf> {
+        let path = dir.join(format!("{}.conf", self.name));
+        fs::write(&path, self.render())?;
+        Ok(path)
+    }
+}
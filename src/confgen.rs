@@ -1,58 +1,481 @@
-use quickcheck::{Arbitrary, Gen, QuickCheck, RngCore};
-use rand::{rngs::StdRng, Rng};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use quickcheck::{Arbitrary, Gen, QuickCheck};
+
+use crate::conf::{self, GatewayConf};
+use crate::monitor::{gateway_clusters, routed_servers};
+use crate::ports::PortBlock;
+use crate::{cluster_name, run_dir, server, server_name, Ports, Server, LOCALHOST};
+
+// small enough that a generated supercluster can be booted
+const MAX_CLUSTERS: usize = 3;
+const MAX_SERVERS_PER_CLUSTER: usize = 3;
+
+const MB: u64 = 1024 * 1024;
+
+// how long a booted supercluster has to form a full mesh
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Clone)]
 struct SuperclusterConf {
     clusters: Vec<ClusterConf>,
 }
 
+impl SuperclusterConf {
+    /// Names every cluster and server by position, so that
+    /// names stay unique while shrinking.
+    fn name(mut self) -> SuperclusterConf {
+        let mut idx = 0;
+        for (c, cluster) in self.clusters.iter_mut().enumerate() {
+            cluster.name = cluster_name(c);
+            for server in &mut cluster.servers {
+                server.name = server_name(idx);
+                idx += 1;
+            }
+        }
+        self
+    }
+
+    /// Claims ports for every server, right before they are
+    /// needed. They stay claimed until the returned block is
+    /// dropped.
+    fn claim_ports(&mut self) -> PortBlock {
+        let servers: Vec<&mut ServerConf> = self
+            .clusters
+            .iter_mut()
+            .flat_map(|cluster| cluster.servers.iter_mut())
+            .collect();
+
+        let block = PortBlock::claim(4 * servers.len()).expect("unable to claim ports");
+        for (server, ports) in servers.into_iter().zip(block.ports().chunks(4)) {
+            server.port = ports[0];
+            server.http_port = ports[1];
+            server.cluster_port = ports[2];
+            server.gateway_port = ports[3];
+        }
+        block
+    }
+
+    fn servers(&self) -> impl Iterator<Item = (&ClusterConf, &ServerConf)> {
+        self.clusters
+            .iter()
+            .flat_map(|cluster| cluster.servers.iter().map(move |server| (cluster, server)))
+    }
+
+    /// The cluster and gateway parts of each server's config.
+    fn topology(&self) -> Vec<conf::ServerConf> {
+        self.servers()
+            .map(|(cluster, server)| {
+                let routes = cluster
+                    .servers
+                    .iter()
+                    .filter(|peer| peer.name != server.name)
+                    .map(|peer| peer.cluster_port)
+                    .collect();
+
+                let gateway = if self.clusters.len() > 1 {
+                    Some(GatewayConf {
+                        port: server.gateway_port,
+                        advertise: server.gateway_port,
+                        remotes: self
+                            .clusters
+                            .iter()
+                            .filter(|remote| remote.name != cluster.name)
+                            .map(|remote| {
+                                let ports = remote.servers.iter().map(|s| s.gateway_port).collect();
+                                (remote.name.clone(), ports)
+                            })
+                            .collect(),
+                    })
+                } else {
+                    None
+                };
+
+                conf::ServerConf {
+                    name: server.name.clone(),
+                    cluster_name: cluster.name.clone(),
+                    cluster_port: server.cluster_port,
+                    advertise: server.cluster_port,
+                    routes,
+                    gateway,
+                }
+            })
+            .collect()
+    }
+
+    /// Renders the config of every server, in order.
+    fn render(&self) -> Vec<String> {
+        self.servers()
+            .zip(self.topology())
+            .map(|((_, server), topology)| topology.render() + &server.render())
+            .collect()
+    }
+
+    /// Writes the config of every server into `dir`,
+    /// returning their paths in order.
+    fn write(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.servers()
+            .zip(self.render())
+            .map(|((_, server), rendered)| {
+                let path = dir.join(format!("{}.conf", server.name));
+                fs::write(&path, rendered)?;
+                Ok(path)
+            })
+            .collect()
+    }
+}
+
 impl Arbitrary for SuperclusterConf {
     fn arbitrary<G: Gen>(g: &mut G) -> SuperclusterConf {
-        let n_clusters: usize = g.gen_range(0..10);
+        let n_clusters = usize::arbitrary(g) % MAX_CLUSTERS + 1;
         SuperclusterConf {
-            clusters: g.gen::<Vec<ClusterConf>>(),
+            clusters: (0..n_clusters).map(|_| ClusterConf::arbitrary(g)).collect(),
         }
+        .name()
     }
 
     fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
         Box::new(
             self.clusters
                 .shrink()
-                .map(|clusters| SuperclusterConf { clusters }),
+                .filter(|clusters| !clusters.is_empty())
+                .map(|clusters| SuperclusterConf { clusters }.name()),
         )
     }
 }
 
 #[derive(Debug, Clone)]
 struct ClusterConf {
+    name: String,
     servers: Vec<ServerConf>,
 }
 
 impl Arbitrary for ClusterConf {
     fn arbitrary<G: Gen>(g: &mut G) -> ClusterConf {
-        todo!()
+        let n_servers = usize::arbitrary(g) % MAX_SERVERS_PER_CLUSTER + 1;
+        ClusterConf {
+            name: String::new(),
+            servers: (0..n_servers).map(|_| ServerConf::arbitrary(g)).collect(),
+        }
     }
 
     fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
-        Box::new(self.servers.shrink().map(|servers| ClusterConf { servers }))
+        let name = self.name.clone();
+        Box::new(
+            self.servers
+                .shrink()
+                .filter(|servers| !servers.is_empty())
+                .map(move |servers| ClusterConf {
+                    name: name.clone(),
+                    servers,
+                }),
+        )
     }
 }
 
+/// The parts of a server's config that don't depend on
+/// the topology. Names and ports are assigned by the
+/// supercluster that the server is part of, ports only once
+/// it gets rendered or booted.
 #[derive(Debug, Clone)]
-struct ServerConf {}
+struct ServerConf {
+    name: String,
+    port: u16,
+    http_port: u16,
+    cluster_port: u16,
+    gateway_port: u16,
+    jetstream: JetStreamLimits,
+    // credentials that clients need, if any
+    auth: Option<Auth>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct JetStreamLimits {
+    max_memory_store: u64,
+    max_file_store: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Auth {
+    user: String,
+    password: String,
+}
+
+impl ServerConf {
+    fn render(&self) -> String {
+        let mut out = format!(
+            "
+port: {}
+http: {}
+
+jetstream {{
+  max_memory_store: {}
+  max_file_store: {}
+}}
+",
+            self.port,
+            self.http_port,
+            self.jetstream.max_memory_store,
+            self.jetstream.max_file_store
+        );
+
+        if let Some(auth) = &self.auth {
+            out.push_str(&format!(
+                "
+authorization {{
+  user: {:?}
+  password: {:?}
+}}
+",
+                auth.user, auth.password
+            ));
+        }
+
+        out
+    }
+
+    /// Connects a client with the credentials that the
+    /// server asks for, if any.
+    fn connect(&self) -> io::Result<nats::Connection> {
+        let options = match &self.auth {
+            Some(auth) => nats::Options::with_user_pass(&auth.user, &auth.password),
+            None => nats::Options::new(),
+        };
+        options.connect(&format!("{}:{}", LOCALHOST, self.port))
+    }
+}
 
 impl Arbitrary for ServerConf {
     fn arbitrary<G: Gen>(g: &mut G) -> ServerConf {
-        todo!()
+        let auth = if bool::arbitrary(g) {
+            Some(Auth {
+                user: format!("user{}", u8::arbitrary(g)),
+                password: format!("password{}", u8::arbitrary(g)),
+            })
+        } else {
+            None
+        };
+
+        ServerConf {
+            name: String::new(),
+            port: 0,
+            http_port: 0,
+            cluster_port: 0,
+            gateway_port: 0,
+            jetstream: JetStreamLimits {
+                max_memory_store: (u64::arbitrary(g) % 256 + 1) * MB,
+                max_file_store: (u64::arbitrary(g) % 1024 + 1) * MB,
+            },
+            auth,
+        }
     }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+        let mut smaller = vec![];
+        if self.auth.is_some() {
+            let mut server = self.clone();
+            server.auth = None;
+            smaller.push(server);
+        }
+        for max_memory_store in shrink_size(self.jetstream.max_memory_store) {
+            let mut server = self.clone();
+            server.jetstream.max_memory_store = max_memory_store;
+            smaller.push(server);
+        }
+        for max_file_store in shrink_size(self.jetstream.max_file_store) {
+            let mut server = self.clone();
+            server.jetstream.max_file_store = max_file_store;
+            smaller.push(server);
+        }
+        Box::new(smaller.into_iter())
+    }
+}
+
+/// Smaller storage limits to try, the minimum of 1 MB first,
+/// then half of `size`.
+fn shrink_size(size: u64) -> Vec<u64> {
+    let mut smaller = vec![];
+    if size > MB {
+        smaller.push(MB);
+    }
+    let half = (size / 2 / MB).max(1) * MB;
+    if half > MB {
+        smaller.push(half);
+    }
+    smaller
+}
+
+/// Describes what the server is still missing from a full
+/// mesh, or returns `None` once it accepts clients with its
+/// credentials (and only with them), routes to every other
+/// server of its cluster and has a gateway to every other
+/// cluster.
+fn missing_links(
+    sc: &SuperclusterConf,
+    cluster: &ClusterConf,
+    server: &ServerConf,
+) -> Option<String> {
+    let expected_routes = cluster.servers.len() - 1;
+    let routes = match routed_servers(LOCALHOST, server.http_port) {
+        Ok(routes) => routes,
+        Err(e) => return Some(format!("{}: /routez failed: {}", server.name, e)),
+    };
+    if routes < expected_routes {
+        return Some(format!(
+            "{}: routes to {} of {} peers",
+            server.name, routes, expected_routes
+        ));
+    }
+
+    if let Err(e) = server.connect() {
+        return Some(format!("{}: client connection failed: {}", server.name, e));
+    }
+    if server.auth.is_some() && nats::connect(&format!("{}:{}", LOCALHOST, server.port)).is_ok() {
+        return Some(format!(
+            "{}: accepted a client without credentials",
+            server.name
+        ));
+    }
+
+    if sc.clusters.len() < 2 {
+        return None;
+    }
+
+    let expected_gateways: HashSet<String> = sc
+        .clusters
+        .iter()
+        .filter(|remote| remote.name != cluster.name)
+        .map(|remote| remote.name.clone())
+        .collect();
+    let gateways = match gateway_clusters(LOCALHOST, server.http_port) {
+        Ok(gateways) => gateways,
+        Err(e) => return Some(format!("{}: /gatewayz failed: {}", server.name, e)),
+    };
+    let missing: Vec<&String> = expected_gateways.difference(&gateways).collect();
+    if !missing.is_empty() {
+        return Some(format!("{}: no gateway to {:?}", server.name, missing));
+    }
+
+    None
 }
 
-fn prop_connectivity(sc: SuperclusterConf) -> bool {
-    false
+/// Boots the supercluster with the same spawning logic as
+/// an exercise run, and checks that its monitoring endpoints
+/// show a full mesh of routes and gateways before
+/// `CONNECT_TIMEOUT`. Uses the nats-server binary in
+/// `NATS_SERVER`, or the one on the `PATH`.
+fn prop_connectivity(mut sc: SuperclusterConf) -> bool {
+    // dropped after the servers
+    let _ports = sc.claim_ports();
+
+    let dir = run_dir().expect("unable to create run directory");
+    let confs = sc.write(&dir).expect("unable to write server configs");
+
+    let path = std::env::var("NATS_SERVER").unwrap_or_else(|_| "nats-server".into());
+
+    // killed when dropped at the end of the property
+    let _servers: Vec<Server> = sc
+        .servers()
+        .zip(confs)
+        .enumerate()
+        .map(|(idx, ((_, conf), path_to_conf))| {
+            let ports = Ports {
+                client: conf.port,
+                http: conf.http_port,
+                cluster: conf.cluster_port,
+                gateway: conf.gateway_port,
+            };
+            server(&path, idx, ports, &dir, path_to_conf)
+        })
+        .collect();
+
+    let start = Instant::now();
+    loop {
+        let missing: Vec<String> = sc
+            .servers()
+            .filter_map(|(cluster, server)| missing_links(&sc, cluster, server))
+            .collect();
+
+        if missing.is_empty() {
+            return true;
+        }
+
+        if start.elapsed() > CONNECT_TIMEOUT {
+            println!(
+                "supercluster did not fully connect within {:?}: {:?}",
+                CONNECT_TIMEOUT, missing
+            );
+            return false;
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Checks that the rendered configs describe a full mesh:
+/// every server routes to the rest of its cluster and has
+/// a gateway to every other cluster, over unique ports.
+fn prop_rendered_mesh(mut sc: SuperclusterConf) -> bool {
+    let _ports = sc.claim_ports();
+
+    let ports: Vec<u16> = sc
+        .servers()
+        .flat_map(|(_, s)| vec![s.port, s.http_port, s.cluster_port, s.gateway_port])
+        .collect();
+    let unique_ports: HashSet<u16> = ports.iter().copied().collect();
+    if unique_ports.len() != ports.len() {
+        return false;
+    }
+
+    let rendered = sc.render();
+
+    sc.servers()
+        .zip(rendered)
+        .all(|((cluster, server), rendered)| {
+            let routes_to_peers = cluster
+                .servers
+                .iter()
+                .filter(|peer| peer.name != server.name)
+                .all(|peer| rendered.contains(&format!("@127.0.0.1:{}\n", peer.cluster_port)));
+
+            let gateways_to_remotes = sc
+                .clusters
+                .iter()
+                .filter(|remote| remote.name != cluster.name)
+                .all(|remote| {
+                    rendered.contains(&format!("name: {:?}, urls", remote.name))
+                        && remote.servers.iter().all(|peer| {
+                            rendered.contains(&format!("@127.0.0.1:{}\"", peer.gateway_port))
+                        })
+                });
+
+            let credentials = match &server.auth {
+                Some(auth) => rendered.contains(&format!(
+                    "user: {:?}\n  password: {:?}",
+                    auth.user, auth.password
+                )),
+                None => true,
+            };
+
+            routes_to_peers && gateways_to_remotes && credentials
+        })
+}
+
+#[test]
+fn rendered_mesh() {
+    QuickCheck::new().quickcheck(prop_rendered_mesh as fn(SuperclusterConf) -> bool);
 }
 
+// boots every generated supercluster, so it needs a
+// nats-server binary and takes a while
 #[test]
+#[ignore]
 pub fn qc() {
     QuickCheck::new()
+        .tests(10)
         .quickcheck(prop_connectivity as fn(SuperclusterConf) -> bool);
 }
@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::{self, Delivery, DurabilityModel, Violation};
+use crate::payload::MessageId;
+
+/// One line of a history file.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    /// milliseconds since the run started
+    pub elapsed_ms: u64,
+    /// the client that performed the operation, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub process: Option<usize>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ClientOp {
+    Publish,
+    Consume,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub(crate) enum Event {
+    /// a client operation is about to start
+    Invoke {
+        op: ClientOp,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value: Option<MessageId>,
+    },
+    /// an acknowledged publish, or a received message
+    Ok {
+        op: ClientOp,
+        #[serde(flatten)]
+        delivery: Delivery,
+    },
+    Fail {
+        op: ClientOp,
+        error: String,
+    },
+    Timeout {
+        op: ClientOp,
+    },
+    /// a received message that failed to decode
+    Corrupt {
+        op: ClientOp,
+        error: String,
+    },
+    Fault {
+        description: String,
+    },
+    /// every consumer read up to the stream's last sequence
+    /// after all faults were healed
+    Drained {
+        last_seq: u64,
+    },
+}
+
+/// Writes a newline-delimited JSON history of a run. Every
+/// entry is flushed right away, so the history survives the
+/// process exiting on a violation.
+pub(crate) struct History {
+    out: LineWriter<File>,
+    start: Instant,
+}
+
+impl History {
+    pub(crate) fn create<P: AsRef<Path>>(path: P, start: Instant) -> io::Result<History> {
+        Ok(History {
+            out: LineWriter::new(File::create(path)?),
+            start,
+        })
+    }
+
+    pub(crate) fn record(&mut self, process: Option<usize>, event: Event) {
+        let entry = Entry {
+            elapsed_ms: self.start.elapsed().as_millis() as u64,
+            process,
+            event,
+        };
+        serde_json::to_writer(&mut self.out, &entry).expect("unable to write history");
+        self.out.write_all(b"\n").expect("unable to write history");
+    }
+}
+
+/// Runs the durability and ordering checks of a live run
+/// against a history file, returning the first violation
+/// found. Availability, liveness and stream audits depend on
+/// the live cluster, so they aren't checked.
+pub fn check<P: AsRef<Path>>(path: P) -> io::Result<Option<Violation>> {
+    let reader = BufReader::new(File::open(path)?);
+
+    let mut model = DurabilityModel::default();
+    let mut deliveries: HashMap<usize, Vec<Delivery>> = HashMap::new();
+    let mut entries = 0;
+    let mut faults = 0;
+    let mut drained = false;
+
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let invalid = |reason: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid history entry on line {}: {}", number + 1, reason),
+            )
+        };
+
+        let entry: Entry = serde_json::from_str(&line).map_err(|e| invalid(e.to_string()))?;
+        entries += 1;
+
+        // only faults and the final drain aren't done by a client
+        let client = entry.process;
+        let process = || client.ok_or_else(|| invalid("client operation without a process".into()));
+
+        let checked = match entry.event {
+            Event::Ok {
+                op: ClientOp::Publish,
+                delivery,
+            } => {
+                process()?;
+                model.acked(delivery.value, delivery.seq)
+            }
+            Event::Ok {
+                op: ClientOp::Consume,
+                delivery,
+            } => {
+                let process = process()?;
+                deliveries.entry(process).or_default().push(delivery);
+                model.delivered(process, &delivery)
+            }
+            Event::Corrupt { error, .. } => Err(Violation {
+                kind: "Corruption",
+                check: "damaged-delivery",
+                description: format!(
+                    "A consumer received a damaged message.
+                        consumer: {}
+                        error: {}",
+                    process()?,
+                    error
+                ),
+            }),
+            Event::Fault { .. } => {
+                faults += 1;
+                Ok(())
+            }
+            Event::Drained { last_seq } => {
+                drained = true;
+                model.check_drained(last_seq)
+            }
+            Event::Invoke { .. } | Event::Fail { .. } | Event::Timeout { .. } => {
+                process()?;
+                Ok(())
+            }
+        };
+
+        if let Err(violation) = checked {
+            println!(
+                "violation after {} entries, at {} ms into the run",
+                entries, entry.elapsed_ms
+            );
+            return Ok(Some(violation));
+        }
+    }
+
+    println!(
+        "checked {} entries with {} faults, {} acknowledged publishes \
+        and {} observed stream sequences",
+        entries,
+        faults,
+        model.acked.len(),
+        model.observed.len()
+    );
+
+    let mut consumers: Vec<_> = deliveries.into_iter().collect();
+    consumers.sort_unstable_by_key(|(id, _)| *id);
+    for (id, deliveries) in consumers {
+        let (duplicates, redeliveries) = model::duplicates_and_redeliveries(&deliveries);
+        println!(
+            "consumer {}: {} deliveries, {} duplicates, {} redeliveries",
+            id,
+            deliveries.len(),
+            duplicates,
+            redeliveries
+        );
+    }
+
+    if !drained {
+        println!("the run never drained its consumers, so lost writes were not checked");
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(op: ClientOp, seq: u64, publisher: u64, redelivered: bool) -> Event {
+        Event::Ok {
+            op,
+            delivery: Delivery {
+                seq,
+                value: MessageId { publisher, seq: 0 },
+                timestamp: 0,
+                redelivered,
+            },
+        }
+    }
+
+    /// Writes the entries to a history file and checks it.
+    fn check_entries(
+        name: &str,
+        entries: Vec<(Option<usize>, Event)>,
+    ) -> io::Result<Option<Violation>> {
+        let path = std::env::temp_dir().join(format!("{}_{}.ndjson", name, std::process::id()));
+        let mut history = History::create(&path, Instant::now()).unwrap();
+        for (process, event) in entries {
+            history.record(process, event);
+        }
+        drop(history);
+
+        let checked = check(&path);
+        let _ = std::fs::remove_file(&path);
+        checked
+    }
+
+    fn kind(checked: io::Result<Option<Violation>>) -> Option<&'static str> {
+        checked.unwrap().map(|violation| violation.kind)
+    }
+
+    #[test]
+    fn clean_history_passes() {
+        let entries = vec![
+            (
+                Some(0),
+                Event::Invoke {
+                    op: ClientOp::Publish,
+                    value: Some(MessageId {
+                        publisher: 0,
+                        seq: 0,
+                    }),
+                },
+            ),
+            (Some(0), ok(ClientOp::Publish, 1, 0, false)),
+            (
+                None,
+                Event::Fault {
+                    description: "pausing server 1".into(),
+                },
+            ),
+            (
+                Some(1),
+                Event::Timeout {
+                    op: ClientOp::Publish,
+                },
+            ),
+            (Some(1), ok(ClientOp::Consume, 1, 0, false)),
+            (Some(1), ok(ClientOp::Consume, 1, 0, true)),
+            (Some(0), ok(ClientOp::Consume, 1, 0, false)),
+            (None, Event::Drained { last_seq: 1 }),
+        ];
+
+        assert_eq!(kind(check_entries("clean", entries)), None);
+    }
+
+    #[test]
+    fn out_of_order_acks_and_deliveries_are_caught() {
+        let acks = vec![
+            (Some(0), ok(ClientOp::Publish, 2, 0, false)),
+            (Some(0), ok(ClientOp::Publish, 1, 0, false)),
+        ];
+        assert_eq!(kind(check_entries("acks", acks)), Some("Ordering"));
+
+        let deliveries = vec![
+            (Some(0), ok(ClientOp::Consume, 2, 0, false)),
+            (Some(0), ok(ClientOp::Consume, 1, 1, false)),
+        ];
+        assert_eq!(
+            kind(check_entries("deliveries", deliveries)),
+            Some("Ordering")
+        );
+    }
+
+    #[test]
+    fn conflicts_lost_acks_and_damage_are_caught() {
+        let conflict = vec![
+            (Some(0), ok(ClientOp::Consume, 1, 0, false)),
+            (Some(1), ok(ClientOp::Consume, 1, 1, false)),
+        ];
+        assert_eq!(
+            kind(check_entries("conflict", conflict)),
+            Some("Correctness")
+        );
+
+        let lost = vec![
+            (Some(0), ok(ClientOp::Publish, 1, 0, false)),
+            (Some(1), ok(ClientOp::Consume, 1, 1, false)),
+            (None, Event::Drained { last_seq: 1 }),
+        ];
+        assert_eq!(kind(check_entries("lost", lost)), Some("Correctness"));
+
+        let corrupt = vec![(
+            Some(0),
+            Event::Corrupt {
+                op: ClientOp::Consume,
+                error: "checksum mismatch".into(),
+            },
+        )];
+        assert_eq!(kind(check_entries("corrupt", corrupt)), Some("Corruption"));
+    }
+
+    #[test]
+    fn client_entries_need_a_process() {
+        let entries = vec![(None, ok(ClientOp::Publish, 1, 0, false))];
+        let err = check_entries("process", entries).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
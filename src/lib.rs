@@ -1,73 +1,290 @@
-use std::collections::{HashMap, HashSet};
-use std::convert::TryInto;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fs::File;
 use std::io;
 use std::mem;
+use std::net::TcpStream;
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command};
-use std::sync::atomic::{AtomicU64, Ordering::SeqCst};
+use std::process::{Child, Command, ExitStatus};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-use rand::seq::{IteratorRandom, SliceRandom};
+use rand::seq::SliceRandom;
 use rand::{rngs::StdRng, Rng, SeedableRng};
 
-use nats::jetstream::{ConsumerConfig, RetentionPolicy, StreamConfig};
+use nats::jetstream::ConsumerConfig;
+use serde::Deserialize;
+
+mod conf;
+#[cfg(test)]
+mod confgen;
+mod history;
+mod liveness;
+mod logs;
+mod model;
+mod monitor;
+mod payload;
+mod ports;
+mod profile;
+mod proxy;
+mod shrink;
+mod storage;
+mod trace;
+
+use conf::{GatewayConf, ServerConf};
+pub use history::check as check_history;
+use history::{ClientOp, Event, History};
+use liveness::Availability;
+pub use model::Violation;
+use model::{Delivery, DurabilityModel};
+use payload::{MessageId, Payload};
+use ports::PortBlock;
+pub use profile::{Op, Profile};
+use proxy::{Degradation, Proxy};
+pub use shrink::shrink;
+use trace::{Decision, Link, TraceWriter};
 
 const STREAM: &str = "exercise_stream";
 
-// generates unique (for this test run) ID
-fn idgen() -> u64 {
-    static IDGEN: AtomicU64 = AtomicU64::new(0);
-    IDGEN.fetch_add(1, SeqCst)
-}
+// how long a stream audit may take before giving up
+const AUDIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// what nats-server exits with when it refuses to start
+const FATAL_EXIT_CODE: i32 = 1;
+
+// how long a paused server has to actually stop
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// how long a started server has to become ready
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// where the local servers listen
+const LOCALHOST: &str = "127.0.0.1";
+
+// how long publishes may go without success before
+// we probe the stream with an acknowledged one
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(1);
+
+// how many of the latest faults to report when the
+// cluster fails to recover from them
+const RECENT_FAULTS: usize = 10;
+
+// how long the cluster has to recover once every
+// fault is healed at the end of a run
+const RECOVERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+// how long consumers have to read up to the end of the
+// stream. longer than the default ack wait, so messages
+// that were lost in flight get redelivered
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(90);
 
 pub struct Cluster {
     clients: Vec<Consumer>,
     servers: Vec<Server>,
     paused: HashSet<usize>,
+    // one proxy per directed (from, to) route link
+    links: HashMap<(usize, usize), Proxy>,
+    // one proxy per server that its clients connect through
+    client_links: Vec<Proxy>,
+    // addresses that servers advertise for implicit routes and
+    // gateways. these stay cut so that gossip can't be used to
+    // sneak around a partition. only held to keep them alive.
+    _advertised: Vec<Proxy>,
+    // the ports of every server, only held to keep them claimed
+    _ports: PortBlock,
     args: Args,
     rng: StdRng,
     unvalidated_consumers: HashSet<usize>,
     durability_model: DurabilityModel,
+    findings: Vec<Finding>,
+    // whether the stream keeps working while its quorum is intact
+    availability: Availability,
+    // whether the stream starts working again once every fault heals
+    recovery: Availability,
+    // when the latest faults were injected, and what they were
+    recent_faults: VecDeque<(Duration, String)>,
+    history: Option<History>,
+    trace: Option<TraceWriter>,
+    // the rest of the schedule when replaying a trace
+    replay: Option<VecDeque<Decision>>,
+    // generates unique (for this test run) IDs
+    next_id: u64,
+    // whether the server logs were scanned for findings yet
+    logs_scanned: bool,
+    // holds the configs, logs, storage and default trace
+    run_dir: PathBuf,
+    // whether the run passed without findings, so that its
+    // run directory can go
+    passed: bool,
+}
+
+/// Something suspicious that isn't a durability violation,
+/// so the run keeps going and reports it at the end.
+#[derive(Debug)]
+pub struct Finding {
+    pub elapsed: Duration,
+    pub description: String,
 }
 
 impl Cluster {
-    pub fn start(args: Args) -> Cluster {
+    pub fn start(mut args: Args) -> Cluster {
         println!("Starting cluster exerciser with seed {}", args.seed);
 
         let rng = SeedableRng::seed_from_u64(args.seed);
 
-        let servers: Vec<Server> = (0..args.servers)
-            .map(|i| server(&args.path, i as u16))
+        let n_servers = args.servers as usize;
+
+        let run_dir = run_dir().expect("unable to create run directory");
+        println!("writing server configs and storage to {:?}", run_dir);
+
+        if args.trace.is_none() && args.replay.is_none() && args.shrink.is_none() {
+            args.trace = Some(run_dir.join(format!("exercise_{}.trace", args.seed)));
+        }
+
+        // each server keeps its ports for the whole run, so
+        // that nothing else gets them while it restarts
+        let port_block = PortBlock::claim(4 * n_servers).expect("unable to claim ports");
+        let ports: Vec<Ports> = port_block.ports().chunks(4).map(Ports::from).collect();
+
+        // servers in the same cluster are linked by routes,
+        // and servers in different clusters by gateways
+        let upstream = |from: usize, to: usize| {
+            if args.cluster_of(from) == args.cluster_of(to) {
+                ports[to].cluster
+            } else {
+                ports[to].gateway
+            }
+        };
+
+        let mut links = HashMap::new();
+        for from in 0..n_servers {
+            for to in 0..n_servers {
+                if from != to {
+                    links.insert((from, to), Proxy::start(upstream(from, to)));
+                }
+            }
+        }
+
+        let cut_proxy = |port: u16| {
+            let proxy = Proxy::start(port);
+            proxy.cut();
+            proxy
+        };
+
+        let mut advertised: Vec<Proxy> = (0..n_servers)
+            .map(|i| cut_proxy(ports[i].cluster))
             .collect();
 
-        // let servers come up
-        std::thread::sleep(std::time::Duration::from_millis(2000));
+        let advertised_gateways: Vec<Proxy> = if args.clusters > 1 {
+            (0..n_servers)
+                .map(|i| cut_proxy(ports[i].gateway))
+                .collect()
+        } else {
+            vec![]
+        };
 
-        println!("creating testing stream {}", STREAM);
+        let client_links: Vec<Proxy> = (0..n_servers)
+            .map(|i| Proxy::start(ports[i].client))
+            .collect();
+
+        let mut servers: Vec<Server> = (0..n_servers)
+            .map(|i| {
+                let cluster = args.cluster_of(i);
+                let routes = (0..n_servers)
+                    .filter(|j| *j != i && args.cluster_of(*j) == cluster)
+                    .map(|j| links[&(i, j)].port())
+                    .collect();
+                let gateway = advertised_gateways.get(i).map(|advertise| GatewayConf {
+                    port: ports[i].gateway,
+                    advertise: advertise.port(),
+                    remotes: (0..args.clusters)
+                        .filter(|c| *c != cluster)
+                        .map(|c| {
+                            let ports = (0..n_servers)
+                                .filter(|j| args.cluster_of(*j) == c)
+                                .map(|j| links[&(i, j)].port())
+                                .collect();
+                            (cluster_name(c), ports)
+                        })
+                        .collect(),
+                });
+                let conf = ServerConf {
+                    name: server_name(i),
+                    cluster_name: cluster_name(cluster),
+                    cluster_port: ports[i].cluster,
+                    advertise: advertised[i].port(),
+                    routes,
+                    gateway,
+                };
+                let conf_path = conf.write(&run_dir).expect("unable to write server config");
+                server(&args.path, i, ports[i], &run_dir, conf_path)
+            })
+            .collect();
+
+        advertised.extend(advertised_gateways);
+
+        let readiness = Readiness {
+            routes: args.servers_per_cluster() - 1,
+            gateways: args.clusters - 1,
+            jetstream_current: true,
+        };
+        let startup = Instant::now();
+        for (idx, server) in servers.iter_mut().enumerate() {
+            match server.await_ready(readiness) {
+                Ok(took) => println!("server {} ready after {:?}", idx, took),
+                Err(e) => panic!("server {} did not become ready: {:?}", idx, e),
+            }
+        }
+        println!(
+            "all {} servers ready after {:?}",
+            n_servers,
+            startup.elapsed()
+        );
+
+        let stream_cluster = args.stream_cluster();
+        println!(
+            "creating testing stream {} in cluster {}",
+            STREAM,
+            cluster_name(stream_cluster)
+        );
 
         {
             let nc = servers[0].nc();
 
             let _ = nc.delete_stream(STREAM);
 
-            nc.create_stream(StreamConfig {
-                num_replicas: args.num_replicas,
-                name: STREAM.to_string(),
-                retention: RetentionPolicy::Limits,
-                ..Default::default()
-            })
+            monitor::create_stream(
+                &nc,
+                STREAM,
+                args.num_replicas,
+                &cluster_name(stream_cluster),
+            )
             .expect("couldn't create exercise_stream");
         }
 
-        let clients: Vec<Consumer> = servers
-            .iter()
+        // the stream lives in a single cluster, so clients are
+        // spread over the clusters first, to send most of their
+        // traffic through gateways
+        let per_cluster = args.servers_per_cluster();
+        let mut placement: Vec<usize> = (0..n_servers).collect();
+        placement.sort_by_key(|idx| (idx % per_cluster, idx / per_cluster));
+
+        let clients: Vec<Consumer> = placement
+            .into_iter()
             .cycle()
             .enumerate()
             .take(args.clients as usize)
-            .map(|(id, s)| {
+            .map(|(id, server)| {
                 let consumer_name = format!("consumer_{}", id);
-                println!("creating testing consumer {}", consumer_name);
+                println!(
+                    "creating testing consumer {} on server {}",
+                    consumer_name, server
+                );
 
-                let nc = s.nc();
+                let nc = connect(client_links[server].port());
                 let conf = ConsumerConfig {
                     deliver_subject: Some(consumer_name.clone()),
                     durable_name: consumer_name.into(),
@@ -77,167 +294,1778 @@ impl Cluster {
                     inner: nc
                         .create_consumer(STREAM, conf)
                         .expect("couldn't create consumer"),
-                    observed: Default::default(),
+                    deliveries: vec![],
+                    validated: 0,
+                    published: 0,
                     id,
+                    server,
                 }
             })
             .collect();
 
+        let history = args.history.as_ref().map(|path| {
+            println!("recording history to {:?}", path);
+            History::create(path, args.start_time).expect("unable to create history file")
+        });
+
+        let trace = args.trace.as_ref().map(|path| {
+            println!("recording schedule to {:?}", path);
+            TraceWriter::create(path, &args.recorded).expect("unable to create trace file")
+        });
+
+        let replay = args.replay.as_ref().map(|path| {
+            let trace = trace::load(path).expect("unable to load replay trace");
+            println!(
+                "replaying {} recorded steps from {:?}",
+                trace.decisions.len(),
+                path
+            );
+            trace.decisions.into()
+        });
+
         Cluster {
             servers,
             clients,
-            rng: rng,
+            rng,
+            availability: Availability::new(
+                Duration::from_secs(0),
+                Duration::from_secs(args.availability_deadline),
+            ),
+            recovery: Availability::new(
+                Duration::from_secs(args.recovery_grace),
+                Duration::from_secs(args.recovery_deadline),
+            ),
+            recent_faults: VecDeque::with_capacity(RECENT_FAULTS),
+            history,
+            trace,
+            replay,
+            next_id: 0,
+            logs_scanned: false,
+            run_dir,
+            passed: false,
             args,
             paused: Default::default(),
+            links,
+            client_links,
+            _advertised: advertised,
+            _ports: port_block,
             durability_model: Default::default(),
             unvalidated_consumers: Default::default(),
+            findings: vec![],
+        }
+    }
+
+    /// Where the schedule of the run gets recorded, if anywhere.
+    pub fn trace(&self) -> Option<&Path> {
+        self.args.trace.as_deref()
+    }
+
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    fn finding(&mut self, description: String) {
+        let finding = Finding {
+            elapsed: self.args.start_time.elapsed(),
+            description,
+        };
+        eprintln!(
+            "finding after {:?}: {}",
+            finding.elapsed, finding.description
+        );
+        self.findings.push(finding);
+    }
+
+    fn idgen(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn record(&mut self, process: Option<usize>, event: Event) {
+        if let Some(history) = &mut self.history {
+            history.record(process, event);
+        }
+    }
+
+    /// Announces a fault that is about to be injected.
+    fn fault(&mut self, description: String) {
+        println!("{}", description);
+
+        self.record(
+            None,
+            Event::Fault {
+                description: description.clone(),
+            },
+        );
+
+        if self.recent_faults.len() == RECENT_FAULTS {
+            self.recent_faults.pop_front();
+        }
+        self.recent_faults
+            .push_back((self.args.start_time.elapsed(), description));
+    }
+
+    pub fn step(&mut self) -> Result<(), Violation> {
+        let start = Instant::now();
+
+        let decision = match &mut self.replay {
+            Some(schedule) => schedule.pop_front(),
+            None => self.decide(),
+        };
+
+        if let Some(decision) = decision {
+            if let Some(trace) = &mut self.trace {
+                trace.record(&decision);
+            }
+            self.apply(decision)?;
+        }
+        self.validate()?;
+
+        if start.elapsed() > PROBE_INTERVAL {
+            // the op blocked for a while, like graceful shutdowns
+            // and audits do, so nobody got a chance to publish or
+            // consume. start measuring availability from scratch.
+            self.availability.update(false);
+            self.recovery.update(false);
+        }
+
+        let backlog = self.has_backlog();
+        self.availability.backlog(backlog);
+        self.recovery.backlog(backlog);
+
+        self.check_availability()?;
+        self.check_recovery()
+    }
+
+    /// Makes every random choice of a step up front. Returns
+    /// `None` if the chosen op has nothing to do right now.
+    fn decide(&mut self) -> Option<Decision> {
+        let may_kill = !self.args.no_kill;
+        // without other replicas to recover from, wiping or
+        // damaging storage legitimately loses data
+        let may_damage = self.args.num_replicas >= 2;
+
+        let decision = match self.args.profile.choose(&mut self.rng) {
+            Op::Restart if may_kill => Decision::Restart {
+                server: self.choose_server()?,
+            },
+            Op::Shutdown if may_kill => Decision::Shutdown {
+                server: self.choose_server()?,
+            },
+            Op::LameDuck if may_kill => Decision::LameDuck {
+                server: self.choose_server()?,
+            },
+            Op::Amnesia if may_kill && may_damage => Decision::Amnesia {
+                server: self.choose_server()?,
+            },
+            Op::Corrupt if may_kill && may_damage => Decision::Corrupt {
+                server: self.choose_server()?,
+                seed: self.rng.gen(),
+            },
+            Op::Rollback if may_kill && may_damage => Decision::Rollback {
+                server: self.choose_snapshotted()?,
+            },
+            Op::Pause => Decision::Pause {
+                server: self.choose_pause()?,
+            },
+            Op::Resume => {
+                let mut paused: Vec<usize> = self.paused.iter().copied().collect();
+                // sorted, so that the choice only depends on the rng
+                paused.sort_unstable();
+                Decision::Resume {
+                    server: *paused.choose(&mut self.rng)?,
+                }
+            }
+            Op::Partition => self.choose_partition()?,
+            Op::Heal if self.links.values().any(Proxy::is_cut) => Decision::Heal,
+            Op::Degrade => Decision::Degrade {
+                link: self.choose_link(),
+            },
+            Op::Restore if self.any_degraded() => Decision::Restore,
+            Op::Publish => Decision::Publish {
+                client: self.rng.gen_range(0..self.clients.len()),
+            },
+            Op::Consume => Decision::Consume {
+                client: self.rng.gen_range(0..self.clients.len()),
+            },
+            _ => return None,
+        };
+
+        Some(decision)
+    }
+
+    fn apply(&mut self, decision: Decision) -> Result<(), Violation> {
+        match decision {
+            Decision::Restart { server } => self.restart_server(server),
+            Decision::Shutdown { server } => {
+                self.graceful_restart(server, libc::SIGTERM, "graceful shutdown")
+            }
+            Decision::LameDuck { server } => {
+                self.graceful_restart(server, libc::SIGUSR2, "lame duck mode")
+            }
+            Decision::Amnesia { server } => return self.amnesia_restart(server),
+            Decision::Corrupt { server, seed } => return self.corrupt_storage(server, seed),
+            Decision::Rollback { server } => return self.rollback_server(server),
+            Decision::Pause { server } => self.pause_server(server),
+            Decision::Resume { server } => self.resume_server(server),
+            Decision::Partition { shape, cut } => self.partition(&shape, cut),
+            Decision::Heal => self.heal(),
+            Decision::Degrade { link } => self.degrade_link(link),
+            Decision::Restore => self.restore_link(),
+            Decision::Publish { client } => return self.publish_from(client),
+            Decision::Consume { client } => {
+                self.consume_from(client)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn restart_server(&mut self, idx: usize) {
+        self.fault(format!("restarting server {}", idx));
+
+        self.servers[idx].restart();
+        self.paused.remove(&idx);
+
+        self.await_restarted(idx);
+    }
+
+    fn amnesia_restart(&mut self, idx: usize) -> Result<(), Violation> {
+        self.fault(format!("restarting server {} with wiped storage", idx));
+
+        self.servers[idx].amnesia_restart();
+        self.paused.remove(&idx);
+
+        self.await_restarted(idx);
+
+        self.audit_stream(idx)
+    }
+
+    fn corrupt_storage(&mut self, idx: usize, seed: u64) -> Result<(), Violation> {
+        // a paused process would keep its open files and state
+        // in memory, and never notice the damage
+        let paused = self.paused.remove(&idx);
+        self.servers[idx].kill();
+
+        let storage_dir = self.servers[idx].storage_dir.clone();
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let damage = match storage::corrupt(&storage_dir, &mut rng) {
+            Ok(damage) => damage,
+            Err(e) => {
+                self.finding(format!(
+                    "unable to corrupt the storage of server {}: {}",
+                    idx, e
+                ));
+                format!("failed: {}", e)
+            }
+        };
+
+        self.fault(format!(
+            "killing {} server {} and corrupting its storage: {}",
+            if paused { "paused" } else { "running" },
+            idx,
+            damage
+        ));
+
+        self.recover_damaged(idx)
+    }
+
+    /// Chooses a server with a storage snapshot to roll back,
+    /// if any can be taken down without exceeding the budget.
+    fn choose_snapshotted(&mut self) -> Option<usize> {
+        let cut = self.current_cut();
+        let snapshotted: Vec<usize> = self
+            .running()
+            .into_iter()
+            .filter(|idx| self.servers[*idx].has_snapshot())
+            .collect();
+        let snapshotted: Vec<usize> = snapshotted
+            .into_iter()
+            .filter(|idx| self.within_budget(Some(*idx), &cut))
+            .collect();
+
+        // empty if nothing was snapshotted yet
+        snapshotted.choose(&mut self.rng).copied()
+    }
+
+    fn rollback_server(&mut self, idx: usize) -> Result<(), Violation> {
+        if !self.servers[idx].has_snapshot() {
+            // only happens when replaying a shrunk schedule
+            return Ok(());
+        }
+
+        self.fault(format!("rolling server {} back to its last snapshot", idx));
+
+        self.servers[idx].kill();
+        self.paused.remove(&idx);
+        if let Err(e) = self.servers[idx].restore_snapshot() {
+            // whatever got restored is started like damaged storage
+            self.finding(format!(
+                "unable to restore the snapshot of server {}: {}",
+                idx, e
+            ));
+        }
+
+        self.recover_damaged(idx)
+    }
+
+    /// Starts a server whose storage was tampered with. If it
+    /// refuses to start, it is started again with wiped
+    /// storage. Either way the stream is audited through it.
+    fn recover_damaged(&mut self, idx: usize) -> Result<(), Violation> {
+        self.servers[idx].respawn();
+
+        match self.servers[idx].await_ready(Readiness::RESTARTED) {
+            Ok(took) => println!("server {} ready after {:?}", idx, took),
+            Err(NotReady::Exited(status)) => {
+                if status.code() == Some(FATAL_EXIT_CODE) {
+                    // refusing to start is a perfectly fine reaction
+                    println!(
+                        "server {} refused to start with damaged storage ({}), \
+                        restarting it with wiped storage",
+                        idx, status
+                    );
+                } else {
+                    // a Go panic exits with 2, and crashes die of a signal
+                    self.finding(format!(
+                        "server {} crashed instead of refusing to start with \
+                        damaged storage ({}), restarting it with wiped storage",
+                        idx, status
+                    ));
+                }
+                self.servers[idx].wipe();
+                self.servers[idx].respawn();
+                self.await_restarted(idx);
+            }
+            Err(NotReady::TimedOut(problem)) => self.finding(format!(
+                "server {} with damaged storage was not ready within {:?} of starting: {}",
+                idx, READY_TIMEOUT, problem
+            )),
+        }
+
+        self.audit_stream(idx)
+    }
+
+    /// Reads the whole stream through a new consumer on the
+    /// given server, and checks that every stream sequence
+    /// that was observed so far is still served with the
+    /// same value.
+    fn audit_stream(&mut self, via: usize) -> Result<(), Violation> {
+        let expected = self.durability_model.observed.clone();
+        let max_expected = if let Some(max) = expected.keys().max() {
+            *max
+        } else {
+            // nothing observed yet
+            return Ok(());
+        };
+
+        println!(
+            "auditing {} observed stream sequences through server {}",
+            expected.len(),
+            via
+        );
+
+        let start = Instant::now();
+        let name = format!("audit_{}", self.idgen());
+
+        let mut consumer = loop {
+            let created = try_connect(self.client_links[via].port()).and_then(|nc| {
+                nc.create_consumer(
+                    STREAM,
+                    ConsumerConfig {
+                        deliver_subject: Some(name.clone()),
+                        durable_name: Some(name.clone()),
+                        ..Default::default()
+                    },
+                )
+            });
+
+            match created {
+                Ok(consumer) => break consumer,
+                Err(e) if start.elapsed() > AUDIT_TIMEOUT => {
+                    self.finding(format!(
+                        "could not start a stream audit through server {}: {:?}",
+                        via, e
+                    ));
+                    return Ok(());
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        };
+        consumer.timeout = Duration::from_millis(100);
+
+        let mut served = HashMap::new();
+        let mut highest = 0;
+
+        while highest < max_expected && start.elapsed() < AUDIT_TIMEOUT {
+            match consumer.process_timeout(decode) {
+                Ok(delivery) => {
+                    served.insert(delivery.seq, delivery.value);
+                    highest = highest.max(delivery.seq);
+                }
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    return Err(self.violation(
+                        "Corruption",
+                        "audit-damage",
+                        format!(
+                            "The stream audit through server {} received a damaged message.
+                            error: {}",
+                            via, e
+                        ),
+                    ))
+                }
+                Err(_) => {}
+            }
+        }
+
+        let _ = consumer.nc.delete_consumer(STREAM, &name);
+
+        for (seq, value) in &served {
+            if let Some(old_value) = expected.get(seq) {
+                if value != old_value {
+                    return Err(self.violation(
+                        "Correctness",
+                        "audit-conflict",
+                        format!(
+                            "The stream audit received a different value for \
+                        a stream sequence than consumers did before.
+                            stream sequence: {}
+                            first observed value: {}
+                            audited value: {}",
+                            seq, old_value, value
+                        ),
+                    ));
+                }
+            }
+        }
+
+        // delivery is in stream order, so anything below the
+        // highest served sequence that we didn't get is gone
+        let mut lost: Vec<u64> = expected
+            .keys()
+            .filter(|seq| **seq <= highest && !served.contains_key(seq))
+            .copied()
+            .collect();
+        lost.sort_unstable();
+
+        if !lost.is_empty() {
+            return Err(self.violation(
+                "Correctness",
+                "audit-lost",
+                format!(
+                    "Previously observed stream sequences are no longer served.
+                    lost stream sequences: {:?}",
+                    lost
+                ),
+            ));
+        }
+
+        if highest < max_expected {
+            self.finding(format!(
+                "stream audit through server {} timed out after reading \
+                up to stream sequence {} of {}",
+                via, highest, max_expected
+            ));
+        }
+
+        self.audit_replica(via, &expected);
+
+        Ok(())
+    }
+
+    /// The audit consumer is served by the stream leader, so
+    /// on its own it doesn't show that the audited server got
+    /// its data back. This checks that the server's own replica
+    /// covers every observed stream sequence again.
+    fn audit_replica(&mut self, idx: usize, expected: &HashMap<u64, MessageId>) {
+        let (min_expected, max_expected) = match (expected.keys().min(), expected.keys().max()) {
+            (Some(min), Some(max)) => (*min, *max),
+            _ => return,
+        };
+
+        let start = Instant::now();
+        let mut last_state = None;
+
+        while start.elapsed() < AUDIT_TIMEOUT {
+            if let Ok(Some(state)) =
+                monitor::replica_state(LOCALHOST, self.servers[idx].http_port, STREAM)
+            {
+                if state.first_seq <= min_expected
+                    && state.last_seq >= max_expected
+                    && state.messages >= expected.len() as u64
+                {
+                    println!(
+                        "replica on server {} holds stream sequences {} to {}",
+                        idx, state.first_seq, state.last_seq
+                    );
+                    return;
+                }
+                last_state = Some(state);
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        let name = server_name(idx);
+        let hosts_stream = last_state.is_some()
+            || self.stream_cluster().is_some_and(|cluster| {
+                cluster.leader.as_ref() == Some(&name)
+                    || cluster.replicas.iter().any(|peer| peer.name == name)
+            });
+
+        if hosts_stream {
+            self.finding(format!(
+                "the replica on server {} did not catch up within {:?}: \
+                it has {:?}, but stream sequences {} to {} ({} of them) were observed",
+                idx,
+                AUDIT_TIMEOUT,
+                last_state,
+                min_expected,
+                max_expected,
+                expected.len()
+            ));
+        } else {
+            println!("server {} hosts no replica of the stream", idx);
+        }
+    }
+
+    fn graceful_restart(&mut self, idx: usize, signal: libc::c_int, mode: &str) {
+        self.fault(format!("restarting server {} through {}", idx, mode));
+
+        if self.paused.remove(&idx) {
+            // a stopped process can't handle the signal
+            self.servers[idx].signal(libc::SIGCONT);
+        }
+
+        let deadline = Duration::from_secs(self.args.shutdown_deadline);
+
+        if !self.servers[idx].graceful_restart(signal, deadline) {
+            self.finding(format!(
+                "unclean shutdown: server {} did not exit within {:?} \
+                after being asked to enter {}, so it was killed",
+                idx, deadline, mode
+            ));
+        }
+
+        self.await_restarted(idx);
+    }
+
+    /// Waits for a restarted server to become ready. Its peers
+    /// may be down or cut off, so it only has to come up with
+    /// JetStream enabled, rather than connect to all of them.
+    /// A server that exits stays down, so faults leave it
+    /// alone until the run finishes and starts it again.
+    fn await_restarted(&mut self, idx: usize) {
+        match self.servers[idx].await_ready(Readiness::RESTARTED) {
+            Ok(took) => println!("server {} ready after {:?}", idx, took),
+            Err(NotReady::Exited(status)) => {
+                self.finding(format!("server {} exited while starting: {}", idx, status))
+            }
+            Err(NotReady::TimedOut(problem)) => self.finding(format!(
+                "server {} was not ready within {:?} of starting: {}",
+                idx, READY_TIMEOUT, problem
+            )),
+        }
+    }
+
+    /// Chooses a server to take down, if any can be
+    /// taken down without exceeding the fault budget.
+    fn choose_server(&mut self) -> Option<usize> {
+        let cut = self.current_cut();
+        let allowed: Vec<usize> = self
+            .running()
+            .into_iter()
+            .filter(|idx| self.within_budget(Some(*idx), &cut))
+            .collect();
+
+        if allowed.is_empty() {
+            None
+        } else {
+            Some(self.choose_target(&allowed))
+        }
+    }
+
+    /// Servers whose process is alive, including paused ones.
+    /// Those that died on their own are left alone until the
+    /// run finishes.
+    fn running(&mut self) -> Vec<usize> {
+        (0..self.servers.len())
+            .filter(|idx| self.servers[*idx].is_running())
+            .collect()
+    }
+
+    /// Links that are currently partitioned, as pairs of
+    /// servers with the lower index first.
+    fn current_cut(&self) -> HashSet<(usize, usize)> {
+        self.links
+            .iter()
+            .filter(|(_, proxy)| proxy.is_cut())
+            .map(|((a, b), _)| (*a.min(b), *a.max(b)))
+            .collect()
+    }
+
+    /// Servers that are paused, dead, or partitioned away,
+    /// if `extra` was taken down as well and the `cut`
+    /// links were partitioned.
+    fn down_servers(
+        &mut self,
+        extra: Option<usize>,
+        cut: &HashSet<(usize, usize)>,
+    ) -> HashSet<usize> {
+        let mut down: HashSet<usize> = self.paused.iter().copied().chain(extra).collect();
+
+        for (idx, server) in self.servers.iter_mut().enumerate() {
+            if !server.is_running() {
+                down.insert(idx);
+            }
+        }
+
+        // route traffic isn't forwarded between servers, so keep
+        // dropping whichever server is cut off from the most of
+        // the others until the rest form a full mesh
+        loop {
+            let up: Vec<usize> = (0..self.servers.len())
+                .filter(|idx| !down.contains(idx))
+                .collect();
+
+            let worst = up
+                .iter()
+                .map(|a| {
+                    let cuts = up
+                        .iter()
+                        .filter(|b| cut.contains(&(*a.min(b), *a.max(b))))
+                        .count();
+                    (cuts, *a)
+                })
+                .max();
+
+            match worst {
+                Some((cuts, idx)) if cuts > 0 => down.insert(idx),
+                _ => return down,
+            };
+        }
+    }
+
+    fn within_budget(&mut self, extra: Option<usize>, cut: &HashSet<(usize, usize)>) -> bool {
+        let limit = self.args.max_faulty.limit(self.args.num_replicas);
+        self.down_servers(extra, cut).len() <= limit
+    }
+
+    /// While the stream's quorum is intact, publishes and
+    /// consumes have to keep succeeding.
+    fn check_availability(&mut self) -> Result<(), Violation> {
+        let cut = self.current_cut();
+        let down = self.down_servers(None, &cut);
+        let intact = down.len() <= FaultBudget::Quorum.limit(self.args.num_replicas);
+
+        self.availability.update(intact);
+
+        if !intact {
+            return Ok(());
+        }
+
+        let failing = self.availability.publishes_failing_for();
+        self.probe(failing, &down)?;
+
+        if let Some(problem) = self.availability.check() {
+            let mut down: Vec<usize> = down.into_iter().collect();
+            down.sort_unstable();
+            return Err(self.violation(
+                "Availability",
+                "quorum-unavailable",
+                format!(
+                    "The stream's quorum was intact, but {}.
+                        servers down: {:?}",
+                    problem, down
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Once every fault has stayed healed for the grace period,
+    /// publishes and consumes have to start succeeding again.
+    fn check_recovery(&mut self) -> Result<(), Violation> {
+        let healed = self.paused.is_empty()
+            && !self.links.values().any(Proxy::is_cut)
+            && !self.any_degraded()
+            && self.servers.iter_mut().all(Server::is_running);
+
+        self.recovery.update(healed);
+
+        if !healed {
+            return Ok(());
+        }
+
+        let failing = self.recovery.publishes_failing_for();
+        self.probe(failing, &HashSet::new())?;
+
+        if let Some(problem) = self.recovery.check() {
+            let recent_faults: Vec<String> = self
+                .recent_faults
+                .iter()
+                .map(|(elapsed, fault)| format!("{:?}: {}", elapsed, fault))
+                .collect();
+            return Err(self.violation(
+                "Liveness",
+                "healed-unavailable",
+                format!(
+                    "Every fault was healed, but {}.
+                        latest faults before that:
+                            {}",
+                    problem,
+                    recent_faults.join("\n                            ")
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Whether some consumer hasn't received every acknowledged
+    /// publish yet, so that consumes have something to get.
+    fn has_backlog(&self) -> bool {
+        let last_acked = self.durability_model.last_acked_seq();
+        self.clients
+            .iter()
+            .any(|c| self.durability_model.highest(c.id) < last_acked)
+    }
+
+    fn any_degraded(&self) -> bool {
+        self.client_links
+            .iter()
+            .chain(self.links.values())
+            .any(Proxy::is_degraded)
+    }
+
+    /// Once publishes have been `failing` for longer than
+    /// `PROBE_INTERVAL`, publishes through a client whose
+    /// server is up to find out whether the stream currently
+    /// accepts writes. Probes are recorded to the trace as
+    /// publishes, so replays take them as steps of their own
+    /// instead of probing whenever their timing says so.
+    fn probe(&mut self, failing: Option<Duration>, down: &HashSet<usize>) -> Result<(), Violation> {
+        if self.replay.is_some() {
+            return Ok(());
+        }
+
+        match failing {
+            Some(failing) if failing > PROBE_INTERVAL => {}
+            _ => return Ok(()),
+        }
+
+        let candidates: Vec<usize> = self
+            .clients
+            .iter()
+            .filter(|c| !down.contains(&c.server))
+            .map(|c| c.id)
+            .collect();
+
+        if candidates.is_empty() {
+            return Ok(());
+        }
+
+        // not using the rng here, because how often we probe
+        // depends on timing and would throw off seeded runs
+        let choice = self.idgen() as usize % candidates.len();
+        let probe = Decision::Publish {
+            client: candidates[choice],
+        };
+        if let Some(trace) = &mut self.trace {
+            trace.record(&probe);
+        }
+        self.apply(probe)
+    }
+
+    /// Picks which of the `candidates` a fault goes to,
+    /// according to `--target`. Falls back to a random
+    /// candidate if the preferred role can't be found
+    /// among them.
+    fn choose_target(&mut self, candidates: &[usize]) -> usize {
+        let target = if self.args.target == Target::Mixed {
+            *[
+                Target::Random,
+                Target::Leader,
+                Target::Follower,
+                Target::MetaLeader,
+            ]
+            .choose(&mut self.rng)
+            .unwrap()
+        } else {
+            self.args.target
+        };
+
+        let preferred: Vec<usize> = match target {
+            Target::Random | Target::Mixed => vec![],
+            Target::Leader => self
+                .stream_cluster()
+                .and_then(|cluster| cluster.leader)
+                .and_then(|leader| server_index(&leader))
+                .into_iter()
+                .collect(),
+            Target::Follower => self
+                .stream_cluster()
+                .map(|cluster| cluster.replicas)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|peer| server_index(&peer.name))
+                .collect(),
+            Target::MetaLeader => self
+                .meta_leader()
+                .and_then(|leader| server_index(&leader))
+                .into_iter()
+                .collect(),
+        };
+
+        let preferred: Vec<usize> = preferred
+            .into_iter()
+            .filter(|idx| candidates.contains(idx))
+            .collect();
+
+        if let Some(idx) = preferred.choose(&mut self.rng) {
+            println!("targeting {:?} server {}", target, idx);
+            *idx
+        } else {
+            if target != Target::Random {
+                println!("no {:?} server found, targeting a random one", target);
+            }
+            *candidates.choose(&mut self.rng).unwrap()
+        }
+    }
+
+    /// Asks any client whose server isn't paused where the
+    /// stream is hosted.
+    fn stream_cluster(&self) -> Option<monitor::StreamCluster> {
+        self.clients
+            .iter()
+            .filter(|c| !self.paused.contains(&c.server))
+            .find_map(|c| monitor::stream_cluster(&c.inner.nc, STREAM).ok())
+    }
+
+    /// Asks any server that isn't paused which server
+    /// leads the JetStream meta group.
+    fn meta_leader(&self) -> Option<String> {
+        (0..self.servers.len())
+            .filter(|idx| !self.paused.contains(idx))
+            .find_map(|idx| monitor::meta_leader(LOCALHOST, self.servers[idx].http_port).ok())
+            .flatten()
+    }
+
+    /// Chooses a server to pause, if any can be paused
+    /// without exceeding the fault budget.
+    fn choose_pause(&mut self) -> Option<usize> {
+        let cut = self.current_cut();
+        let running: Vec<usize> = self
+            .running()
+            .into_iter()
+            .filter(|idx| !self.paused.contains(idx))
+            .collect();
+        let running: Vec<usize> = running
+            .into_iter()
+            .filter(|idx| self.within_budget(Some(*idx), &cut))
+            .collect();
+
+        if running.is_empty() {
+            // all servers are already paused or dead, or pausing anything
+            // else would exceed the fault budget
+            return None;
+        }
+
+        Some(self.choose_target(&running))
+    }
+
+    fn pause_server(&mut self, idx: usize) {
+        if self.paused.contains(&idx) || !self.servers[idx].is_running() {
+            // only happens when replaying a shrunk schedule
+            return;
+        }
+
+        self.fault(format!("pausing server {}", idx));
+
+        self.servers[idx].signal(libc::SIGSTOP);
+
+        self.paused.insert(idx);
+
+        // rollbacks are the only use of snapshots, and they
+        // need other replicas to recover from
+        let may_roll_back = !self.args.no_kill
+            && self.args.num_replicas >= 2
+            && self.args.profile.weight(Op::Rollback) > 0;
+
+        if may_roll_back {
+            self.snapshot_paused(idx);
+        }
+    }
+
+    /// Copies the storage of a paused server once every one of
+    /// its threads stopped, which makes for a crash-consistent
+    /// image that we can roll back to later on.
+    fn snapshot_paused(&mut self, idx: usize) {
+        if !self.servers[idx].await_stopped() {
+            self.finding(format!(
+                "paused server {} did not stop within {:?}, so its storage \
+                was not snapshotted",
+                idx, STOP_TIMEOUT
+            ));
+            return;
+        }
+
+        if let Err(e) = self.servers[idx].snapshot() {
+            // a partial copy is no image to roll back to
+            self.servers[idx].drop_snapshot();
+            self.finding(format!(
+                "unable to snapshot the storage of paused server {}: {}",
+                idx, e
+            ));
+        }
+    }
+
+    fn resume_server(&mut self, idx: usize) {
+        if !self.paused.contains(&idx) {
+            // only happens when replaying a shrunk schedule
+            return;
+        }
+
+        self.fault(format!("resuming server {}", idx));
+
+        self.servers[idx].signal(libc::SIGCONT);
+
+        self.paused.remove(&idx);
+    }
+
+    /// Chooses links to cut, unless that would exceed
+    /// the fault budget.
+    fn choose_partition(&mut self) -> Option<Decision> {
+        let n = self.servers.len();
+        if n < 2 {
+            // nothing to partition
+            return None;
+        }
+
+        let mut order: Vec<usize> = (0..n).collect();
+        order.shuffle(&mut self.rng);
+
+        let (shape, cut) = match self.rng.gen_range(0..4) {
+            0 => {
+                let (alone, rest) = order.split_at(1);
+                ("isolated node", links_between(alone, rest))
+            }
+            1 => {
+                let split = self.rng.gen_range(1..=n / 2);
+                let (minority, majority) = order.split_at(split);
+                ("majority/minority", links_between(minority, majority))
+            }
+            2 if n >= 3 => {
+                // order[0] is the bridge, which can still reach both sides
+                let split = self.rng.gen_range(2..n);
+                let (left, right) = order[1..].split_at(split - 1);
+                ("bridge", links_between(left, right))
+            }
+            _ => {
+                let mut cut = vec![];
+                for a in 0..n {
+                    for b in a + 1..n {
+                        if self.rng.gen_bool(0.5) {
+                            cut.push((a, b));
+                        }
+                    }
+                }
+                ("random links", cut)
+            }
+        };
+
+        let undirected: HashSet<(usize, usize)> =
+            cut.iter().map(|(a, b)| (*a.min(b), *a.max(b))).collect();
+        if !self.within_budget(None, &undirected) {
+            println!(
+                "skipping partition ({}) that would exceed the fault budget",
+                shape
+            );
+            return None;
+        }
+
+        Some(Decision::Partition {
+            shape: shape.into(),
+            cut,
+        })
+    }
+
+    fn partition(&mut self, shape: &str, cut: Vec<(usize, usize)>) {
+        self.fault(format!(
+            "partitioning servers ({}), cutting links {:?}",
+            shape, cut
+        ));
+
+        let cut: HashSet<(usize, usize)> = cut
+            .into_iter()
+            .flat_map(|(a, b)| vec![(a, b), (b, a)])
+            .collect();
+
+        for (link, proxy) in &self.links {
+            if cut.contains(link) {
+                proxy.cut();
+            } else {
+                proxy.heal();
+            }
+        }
+    }
+
+    fn heal(&mut self) {
+        self.fault("healing all partitions".into());
+
+        for proxy in self.links.values() {
+            proxy.heal();
         }
     }
 
-    pub fn step(&mut self) {
-        match self.rng.gen_range(0..1000) {
-            0..=5 => self.restart_server(),
-            6..=40 => self.pause_server(),
-            41..=90 => self.resume_server(),
-            91..=200 => self.publish(),
-            201..=1000 => self.consume(),
-            _ => unreachable!("impossible choice"),
+    fn choose_link(&mut self) -> Link {
+        let n = self.servers.len();
+
+        if n < 2 || self.rng.gen_bool(0.5) {
+            Link::Client(self.rng.gen_range(0..n))
+        } else {
+            let a = self.rng.gen_range(0..n);
+            let b = (a + self.rng.gen_range(1..n)) % n;
+            Link::Route(a, b)
         }
-        self.validate();
     }
 
-    fn restart_server(&mut self) {
-        if self.args.no_kill {
-            return;
-        }
-        let idx = self.rng.gen_range(0..self.servers.len());
-        println!("restarting server {}", idx);
+    fn degrade_link(&mut self, link: Link) {
+        let degradation = Degradation {
+            delay: Duration::from_millis(self.args.link_delay),
+            jitter: Duration::from_millis(self.args.link_jitter),
+            bandwidth: self.args.link_bandwidth,
+            reset_probability: self.args.link_resets.min(1000) as f64 / 1000.,
+        };
 
-        self.servers[idx].restart();
-        self.paused.remove(&idx);
+        match link {
+            Link::Client(idx) => {
+                self.fault(format!("degrading client link to server {}", idx));
+                self.client_links[idx].degrade(degradation);
+            }
+            Link::Route(a, b) => {
+                // only one route survives between a pair of servers,
+                // but we don't know which direction dialed it
+                self.fault(format!(
+                    "degrading route link between servers {} and {}",
+                    a, b
+                ));
+                self.links[&(a, b)].degrade(degradation);
+                self.links[&(b, a)].degrade(degradation);
+            }
+        }
     }
 
-    fn pause_server(&mut self) {
-        if self.paused.len() == self.servers.len() {
-            // all servers already paused
-            return;
+    fn restore_link(&mut self) {
+        self.fault("restoring all degraded links".into());
+
+        for proxy in self.client_links.iter().chain(self.links.values()) {
+            proxy.restore();
         }
-        let mut idx = self.rng.gen_range(0..self.servers.len());
+    }
+
+    /// Publishes the client's next message and waits for its ack.
+    fn publish_from(&mut self, id: usize) -> Result<(), Violation> {
+        let message = MessageId {
+            publisher: id as u64,
+            seq: self.clients[id].published,
+        };
+        self.clients[id].published += 1;
+
+        self.record(
+            Some(id),
+            Event::Invoke {
+                op: ClientOp::Publish,
+                value: Some(message),
+            },
+        );
+
+        let payload = Payload::new(message, self.args.payload_padding);
+        let seq = match publish_acked(&self.clients[id].inner.nc, &payload.encode()) {
+            Ok(seq) => seq,
+            Err(e) => {
+                let event = if e.kind() == io::ErrorKind::TimedOut {
+                    Event::Timeout {
+                        op: ClientOp::Publish,
+                    }
+                } else {
+                    Event::Fail {
+                        op: ClientOp::Publish,
+                        error: e.to_string(),
+                    }
+                };
+                self.record(Some(id), event);
+                return Ok(());
+            }
+        };
+
+        self.record(
+            Some(id),
+            Event::Ok {
+                op: ClientOp::Publish,
+                delivery: Delivery {
+                    seq,
+                    value: message,
+                    timestamp: payload.timestamp,
+                    redelivered: false,
+                },
+            },
+        );
 
-        while self.paused.contains(&idx) {
-            idx = self.rng.gen_range(0..self.servers.len());
+        // unacknowledged publishes may or may not have made
+        // it into the stream, so only acks are recorded
+        if let Err(v) = self.durability_model.acked(message, seq) {
+            return Err(self.report(v));
         }
+        self.availability.published();
+        self.recovery.published();
 
-        println!("pausing server {}", idx);
+        Ok(())
+    }
 
-        let pid = self.servers[idx].child.as_ref().unwrap().id();
+    /// Returns `true` if the client received a message.
+    fn consume_from(&mut self, id: usize) -> Result<bool, Violation> {
+        self.record(
+            Some(id),
+            Event::Invoke {
+                op: ClientOp::Consume,
+                value: None,
+            },
+        );
 
-        unsafe {
-            if libc::kill(pid as libc::pid_t, libc::SIGSTOP) != 0 {
-                panic!("{:?}", io::Error::last_os_error());
+        let c = &mut self.clients[id];
+        let proc_ret: io::Result<Delivery> = c.inner.process_timeout(decode);
+
+        match proc_ret {
+            Ok(delivery) => {
+                c.deliveries.push(delivery);
+                self.unvalidated_consumers.insert(c.id);
+                self.availability.consumed();
+                self.recovery.consumed();
+                self.record(
+                    Some(id),
+                    Event::Ok {
+                        op: ClientOp::Consume,
+                        delivery,
+                    },
+                );
+                Ok(true)
+            }
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                self.record(
+                    Some(id),
+                    Event::Corrupt {
+                        op: ClientOp::Consume,
+                        error: e.to_string(),
+                    },
+                );
+                Err(self.violation(
+                    "Corruption",
+                    "damaged-delivery",
+                    format!(
+                        "A consumer received a damaged message.
+                            consumer: {}
+                            error: {}",
+                        id, e
+                    ),
+                ))
+            }
+            Err(e) => {
+                let event = if e.kind() == io::ErrorKind::TimedOut {
+                    Event::Timeout {
+                        op: ClientOp::Consume,
+                    }
+                } else {
+                    Event::Fail {
+                        op: ClientOp::Consume,
+                        error: e.to_string(),
+                    }
+                };
+                self.record(Some(id), event);
+                Ok(false)
             }
         }
+    }
 
-        self.paused.insert(idx);
+    /// Takes every step of the run, or steps until a violation
+    /// with `--burn-in`, then finishes it. The server logs are
+    /// scanned either way.
+    pub fn run(&mut self) -> Result<(), Violation> {
+        let result = self.steps_then_finish();
+        self.scan_logs();
+        self.passed = result.is_ok() && self.findings.is_empty();
+        result
     }
 
-    fn resume_server(&mut self) {
-        if self.paused.is_empty() {
-            // nothing ot resume
-            return;
+    fn steps_then_finish(&mut self) -> Result<(), Violation> {
+        let steps = if self.args.burn_in {
+            u64::MAX
+        } else {
+            self.args.steps
+        };
+
+        for _ in 0..steps {
+            self.step()?;
         }
 
-        let idx = *self.paused.iter().choose(&mut self.rng).unwrap();
+        self.finish()
+    }
 
-        println!("resuming server {}", idx);
+    /// Ends the run by healing every fault, waiting for the
+    /// stream to recover and draining every consumer to the
+    /// end of the stream. Then checks that every sequence in
+    /// the stream was observed, and that no acknowledged
+    /// publish was lost along the way.
+    pub fn finish(&mut self) -> Result<(), Violation> {
+        println!("healing all faults before draining consumers");
 
-        let pid = self.servers[idx].child.as_ref().unwrap().id();
+        for idx in mem::take(&mut self.paused) {
+            self.servers[idx].signal(libc::SIGCONT);
+        }
+        for proxy in self.links.values().chain(&self.client_links) {
+            proxy.heal();
+            proxy.restore();
+        }
+        for idx in 0..self.servers.len() {
+            if !self.servers[idx].is_running() {
+                println!("restarting dead server {}", idx);
+                self.servers[idx].respawn();
+                self.await_restarted(idx);
+            }
+        }
 
-        unsafe {
-            if libc::kill(pid as libc::pid_t, libc::SIGCONT) != 0 {
-                panic!("{:?}", io::Error::last_os_error());
+        let last_seq = self.await_recovery()?;
+
+        println!("draining consumers up to stream sequence {}", last_seq);
+
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        loop {
+            let lagging: Vec<usize> = self
+                .clients
+                .iter()
+                .filter(|c| self.durability_model.highest(c.id) < last_seq)
+                .map(|c| c.id)
+                .collect();
+
+            let gaps = self.durability_model.gaps(last_seq);
+
+            if lagging.is_empty() && gaps.is_empty() {
+                break;
+            }
+
+            if Instant::now() > deadline {
+                return Err(self.violation(
+                    "Availability",
+                    "drain-timeout",
+                    format!(
+                        "Consumers could not read the whole stream after all faults healed.
+                            stream last sequence: {}
+                            lagging consumers (id, highest sequence): {:?}
+                            unobserved stream sequences: {} (first few: {:?})",
+                        last_seq,
+                        lagging
+                            .iter()
+                            .map(|id| (*id, self.durability_model.highest(*id)))
+                            .collect::<Vec<_>>(),
+                        gaps.len(),
+                        &gaps[..gaps.len().min(20)],
+                    ),
+                ));
+            }
+
+            // keep reading from everyone, since unobserved
+            // sequences may be redelivered to any of them
+            for id in 0..self.clients.len() {
+                self.consume_from(id)?;
             }
+            self.validate()?;
         }
 
-        self.paused.remove(&idx);
+        self.record(None, Event::Drained { last_seq });
+
+        if let Err(v) = self.durability_model.check_drained(last_seq) {
+            return Err(self.report(v));
+        }
+
+        println!(
+            "observed all {} stream sequences, including {} acknowledged publishes",
+            last_seq,
+            self.durability_model.acked.len()
+        );
+
+        for c in &self.clients {
+            let (duplicates, redeliveries) = model::duplicates_and_redeliveries(&c.deliveries);
+            println!(
+                "consumer {}: {} deliveries, {} duplicates, {} redeliveries",
+                c.id,
+                c.deliveries.len(),
+                duplicates,
+                redeliveries
+            );
+        }
+
+        Ok(())
     }
 
-    fn publish(&mut self) {
-        let c = self.clients.choose(&mut self.rng).unwrap();
-        let data = idgen().to_le_bytes();
-        c.inner.nc.publish(STREAM, data).unwrap();
+    /// Reports lines of any server's logs that match one of
+    /// the fatal patterns or `--log-patterns` as findings.
+    fn scan_logs(&mut self) {
+        self.logs_scanned = true;
+
+        let patterns: Vec<String> = logs::FATAL_PATTERNS
+            .iter()
+            .map(|pattern| pattern.to_string())
+            .chain(self.args.log_patterns.iter().cloned())
+            .collect();
+
+        let paths: Vec<PathBuf> = self
+            .servers
+            .iter()
+            .flat_map(|server| server.logs.iter().cloned())
+            .collect();
+
+        println!("scanning {} server logs for {:?}", paths.len(), patterns);
+
+        for path in paths {
+            let matches = match logs::scan(&path, &patterns) {
+                Ok(matches) => matches,
+                Err(e) => {
+                    self.finding(format!("unable to scan server log {:?}: {}", path, e));
+                    continue;
+                }
+            };
+
+            for m in matches {
+                self.finding(format!(
+                    "server log {:?} has {} lines matching {:?}, first on line {}: {}",
+                    path, m.count, m.pattern, m.first_line_number, m.first_line
+                ));
+            }
+        }
     }
 
-    fn consume(&mut self) {
-        let c = self.clients.choose_mut(&mut self.rng).unwrap();
-        let proc_ret: io::Result<(u64, u64)> = c.inner.process_timeout(|msg| {
-            let info = msg.jetstream_message_info().unwrap();
+    /// Waits for the stream to have a leader and every replica
+    /// to be caught up, returning the stream's last sequence.
+    fn await_recovery(&mut self) -> Result<u64, Violation> {
+        println!("waiting for the stream to recover");
 
-            let id = u64::from_le_bytes((&*msg.data).try_into().unwrap());
-            Ok((info.stream_seq, id))
-        });
+        let deadline = Instant::now() + RECOVERY_TIMEOUT;
+        let mut last_info = None;
+
+        while Instant::now() < deadline {
+            let info = self
+                .clients
+                .iter()
+                .find_map(|c| monitor::stream_info(&c.inner.nc, STREAM).ok());
+
+            if let Some(info) = info {
+                let healthy = info.cluster.leader.is_some()
+                    && info
+                        .cluster
+                        .replicas
+                        .iter()
+                        .all(|peer| peer.current && !peer.offline);
+
+                if healthy {
+                    return Ok(info.state.last_seq);
+                }
+                last_info = Some(info);
+            }
 
-        if let Ok((seq, id)) = proc_ret {
-            c.observed.insert(seq, id);
-            self.unvalidated_consumers.insert(c.id);
+            std::thread::sleep(PROBE_INTERVAL);
         }
+
+        Err(self.violation(
+            "Availability",
+            "recovery-timeout",
+            format!(
+                "The stream did not recover within {:?} after all faults healed.
+                    last stream info: {:?}",
+                RECOVERY_TIMEOUT, last_info
+            ),
+        ))
     }
 
-    fn validate(&mut self) {
+    fn validate(&mut self) -> Result<(), Violation> {
         // assert all consumers have witnessed messages in the correct order
         let unvalidated_consumers = mem::take(&mut self.unvalidated_consumers);
 
         for id in unvalidated_consumers {
             let c = &mut self.clients[id];
 
-            let observed = mem::take(&mut c.observed);
-
-            for (id, value) in observed {
-                if let Some(old_value) = self.durability_model.observed.insert(id, value) {
-                    if value != old_value {
-                        eprintln!(
-                            "
-                            Correctness violation detected after running for {:?}.
-                            Consumers received different values for the same \
-                            stream sequence.
-                                stream sequence: {}
-                                first observed value: {}
-                                second observed value: {}
-                                schedule replay seed: {}
-                            ",
-                            self.args.start_time.elapsed(),
-                            id,
-                            old_value,
-                            value,
-                            self.args.seed
-                        );
-                        std::process::exit(1);
-                    }
+            let deliveries = c.deliveries[c.validated..].to_vec();
+            c.validated = c.deliveries.len();
+
+            for delivery in deliveries {
+                if let Err(v) = self.durability_model.delivered(id, &delivery) {
+                    return Err(self.report(v));
                 }
             }
         }
+
+        Ok(())
+    }
+
+    fn violation(&self, kind: &'static str, check: &'static str, description: String) -> Violation {
+        self.report(Violation {
+            kind,
+            check,
+            description,
+        })
+    }
+
+    /// Prints a violation along with how to replay it.
+    fn report(&self, violation: Violation) -> Violation {
+        let replay = match (&self.args.trace, &self.args.replay) {
+            (Some(path), _) | (None, Some(path)) => format!("--replay={}", path.display()),
+            (None, None) => "no trace was recorded".into(),
+        };
+        eprintln!(
+            "
+            {} violation ({}) detected after running for {:?}.
+            {}
+                schedule replay seed: {}
+                schedule replay trace: {}
+            ",
+            violation.kind,
+            violation.check,
+            self.args.start_time.elapsed(),
+            violation.description,
+            self.args.seed,
+            replay
+        );
+        violation
+    }
+}
+
+// what JetStream replies to a publish
+#[derive(Deserialize)]
+struct PubAck {
+    seq: Option<u64>,
+    error: Option<serde_json::Value>,
+}
+
+/// Publishes to the stream and waits for JetStream to
+/// acknowledge it, returning the assigned stream sequence.
+fn publish_acked(nc: &nats::Connection, data: &[u8]) -> io::Result<u64> {
+    let msg = nc.request_timeout(STREAM, data, PUBLISH_TIMEOUT)?;
+    let ack: PubAck = serde_json::from_slice(&msg.data)?;
+
+    match (ack.seq, ack.error) {
+        (_, Some(error)) => Err(io::Error::other(error.to_string())),
+        (Some(seq), None) => Ok(seq),
+        (None, None) => Err(io::Error::other("publish ack without a sequence")),
     }
 }
 
+fn decode(msg: &nats::Message) -> io::Result<Delivery> {
+    let info = msg.jetstream_message_info().unwrap();
+
+    let payload = Payload::decode(&msg.data).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("stream sequence {}: {}", info.stream_seq, e),
+        )
+    })?;
+
+    Ok(Delivery {
+        seq: info.stream_seq,
+        value: payload.id,
+        timestamp: payload.timestamp,
+        redelivered: info.delivered > 1,
+    })
+}
+
 struct Server {
     child: Option<Child>,
     port: u16,
-    storage_dir: String,
+    http_port: u16,
+    storage_dir: PathBuf,
+    snapshot_dir: PathBuf,
     path: PathBuf,
-    idx: u16,
+    conf: PathBuf,
+    spawned_at: Instant,
+    idx: usize,
+    run_dir: PathBuf,
+    // the output of every incarnation of the process
+    logs: Vec<PathBuf>,
+}
+
+/// Why a server didn't become ready.
+#[derive(Debug)]
+enum NotReady {
+    Exited(ExitStatus),
+    // with the last thing it was missing
+    TimedOut(String),
+}
+
+/// What a server has to reach to count as ready.
+#[derive(Debug, Clone, Copy)]
+pub struct Readiness {
+    /// how many other servers of its cluster it routes to
+    pub routes: usize,
+    /// how many other clusters it has gateways to
+    pub gateways: usize,
+    /// whether JetStream has to be current with the rest of
+    /// the cluster, rather than just enabled
+    pub jetstream_current: bool,
+}
+
+impl Readiness {
+    const RESTARTED: Readiness = Readiness {
+        routes: 0,
+        gateways: 0,
+        jetstream_current: false,
+    };
+}
+
+/// Checks whether a server accepts client connections,
+/// reports itself healthy through its monitoring port with
+/// JetStream enabled, and is connected to as many servers
+/// and clusters as `readiness` asks for. Returns what it's
+/// still missing otherwise.
+pub fn check_ready(
+    host: &str,
+    client_port: u16,
+    http_port: u16,
+    readiness: Readiness,
+) -> Result<(), String> {
+    TcpStream::connect((host, client_port))
+        .map_err(|e| format!("client port not accepting connections: {}", e))?;
+
+    monitor::healthz(host, http_port, !readiness.jetstream_current)
+        .map_err(|e| format!("not healthy: {}", e))?;
+
+    if !monitor::jetstream_enabled(host, http_port).map_err(|e| e.to_string())? {
+        return Err("JetStream is not enabled".into());
+    }
+
+    let routes = monitor::routed_servers(host, http_port).map_err(|e| e.to_string())?;
+    if routes < readiness.routes {
+        return Err(format!(
+            "routes to {} of {} servers",
+            routes, readiness.routes
+        ));
+    }
+
+    if readiness.gateways > 0 {
+        let gateways = monitor::gateway_clusters(host, http_port)
+            .map_err(|e| e.to_string())?
+            .len();
+        if gateways < readiness.gateways {
+            return Err(format!(
+                "gateways to {} of {} clusters",
+                gateways, readiness.gateways
+            ));
+        }
+    }
+
+    Ok(())
 }
 
 impl Server {
     fn nc(&self) -> nats::Connection {
-        nats::connect(&format!("localhost:{}", self.port)).unwrap()
+        connect(self.port)
+    }
+
+    /// Does nothing if the process already exited, since its
+    /// pid may belong to another process by now.
+    fn signal(&mut self, signal: libc::c_int) {
+        if !self.is_running() {
+            return;
+        }
+        let pid = self.child.as_ref().unwrap().id();
+
+        unsafe {
+            if libc::kill(pid as libc::pid_t, signal) != 0 {
+                panic!("{:?}", io::Error::last_os_error());
+            }
+        }
+    }
+
+    /// Waits for every thread of a process that was sent
+    /// SIGSTOP to actually stop, since the signal is delivered
+    /// asynchronously. Returns `false` if that doesn't happen
+    /// within `STOP_TIMEOUT`, or the process is gone.
+    fn await_stopped(&mut self) -> bool {
+        if !self.is_running() {
+            return false;
+        }
+        let pid = self.child.as_ref().unwrap().id();
+
+        let start = Instant::now();
+        while start.elapsed() < STOP_TIMEOUT {
+            if threads_stopped(pid) {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        false
+    }
+
+    /// Reaps the process if it exited on its own.
+    fn is_running(&mut self) -> bool {
+        let exited = match self.child.as_mut() {
+            Some(child) => child.try_wait().unwrap().is_some(),
+            None => return false,
+        };
+        if exited {
+            self.child = None;
+        }
+        !exited
     }
 
+    /// Does nothing if the process already exited.
+    fn kill(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            child.kill().unwrap();
+            child.wait().unwrap();
+        }
+    }
+
+    /// Kills the server with SIGKILL and starts it again on
+    /// the same storage, so it has to recover from whatever
+    /// it wrote to disk. `amnesia_restart` wipes it instead.
     fn restart(&mut self) {
-        let mut child = self.child.take().unwrap();
-        child.kill().unwrap();
-        child.wait().unwrap();
+        self.kill();
+        self.respawn();
+    }
+
+    /// Waits until the server is ready, returning how long
+    /// it took since the process was spawned.
+    fn await_ready(&mut self, readiness: Readiness) -> Result<Duration, NotReady> {
+        loop {
+            let problem = match check_ready(LOCALHOST, self.port, self.http_port, readiness) {
+                Ok(()) => return Ok(self.spawned_at.elapsed()),
+                Err(problem) => problem,
+            };
+
+            if let Some(status) = self.child.as_mut().unwrap().try_wait().unwrap() {
+                self.child = None;
+                return Err(NotReady::Exited(status));
+            }
+
+            if self.spawned_at.elapsed() > READY_TIMEOUT {
+                return Err(NotReady::TimedOut(problem));
+            }
+
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Sends `signal` and gives the process until `deadline`
+    /// to exit on its own before killing it, then starts it
+    /// again. Returns `false` if it had to be killed.
+    fn graceful_restart(&mut self, signal: libc::c_int, deadline: Duration) -> bool {
+        self.signal(signal);
+
+        let mut child = match self.child.take() {
+            Some(child) => child,
+            None => {
+                // it was already dead, so there's nothing to shut down
+                self.respawn();
+                return true;
+            }
+        };
+        let start = Instant::now();
+
+        let exited = loop {
+            if child.try_wait().unwrap().is_some() {
+                break true;
+            }
+            if start.elapsed() > deadline {
+                child.kill().unwrap();
+                child.wait().unwrap();
+                break false;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+
+        self.respawn();
+
+        exited
+    }
+
+    /// Like `restart`, but the server comes back with an
+    /// empty storage directory, as if its disk was replaced.
+    fn amnesia_restart(&mut self) {
+        self.kill();
+        self.wipe();
+        self.respawn();
+    }
+
+    fn wipe(&self) {
+        let _ = std::fs::remove_dir_all(&self.storage_dir);
+    }
+
+    fn snapshot(&self) -> io::Result<()> {
+        storage::copy_dir(&self.storage_dir, &self.snapshot_dir)
+    }
+
+    fn drop_snapshot(&self) {
+        let _ = std::fs::remove_dir_all(&self.snapshot_dir);
+    }
+
+    fn has_snapshot(&self) -> bool {
+        self.snapshot_dir.exists()
+    }
+
+    /// Replaces the storage directory of a stopped server
+    /// with its last snapshot.
+    fn restore_snapshot(&self) -> io::Result<()> {
+        storage::copy_dir(&self.snapshot_dir, &self.storage_dir)
+    }
+
+    /// Starts a new incarnation of the process on whatever
+    /// is in its storage directory. Only `server` clears it,
+    /// when the run starts.
+    fn respawn(&mut self) {
+        self.spawned_at = Instant::now();
+
+        let log = self
+            .run_dir
+            .join(format!("server_{}.{}.log", self.idx, self.logs.len()));
+        let stdout = File::create(&log).expect("unable to create server log");
+        let stderr = stdout.try_clone().expect("unable to create server log");
+        self.logs.push(log);
+
+        let mut command = Command::new(&self.path);
 
-        *self = server(&self.path, self.idx);
+        command
+            .args(["--port", &self.port.to_string()])
+            .args(["-m", &self.http_port.to_string()])
+            .arg("-js")
+            .arg("-sd")
+            .arg(&self.storage_dir)
+            .arg("-c")
+            .arg(&self.conf)
+            .arg("-V")
+            .arg("-D")
+            .stdout(stdout)
+            .stderr(stderr);
+
+        self.child = Some(command.spawn().expect("unable to spawn nats-server"));
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        // the run panicked, or wasn't driven through `run`
+        if !self.logs_scanned {
+            self.scan_logs();
+        }
+
+        if self.passed && !self.args.keep_run_dir {
+            // stops the servers from writing to it
+            self.servers.clear();
+            self.trace = None;
+            println!(
+                "removing the run directory {:?} of a passed run",
+                self.run_dir
+            );
+            let _ = std::fs::remove_dir_all(&self.run_dir);
+        }
     }
 }
 
@@ -248,77 +2076,292 @@ impl Drop for Server {
             child.wait().unwrap();
         }
         let _ = std::fs::remove_dir_all(&self.storage_dir);
+        let _ = std::fs::remove_dir_all(&self.snapshot_dir);
+    }
+}
+
+/// Whether every thread of the process is stopped, going by
+/// the state in their `/proc` `stat` files.
+fn threads_stopped(pid: u32) -> bool {
+    let tasks = match std::fs::read_dir(format!("/proc/{}/task", pid)) {
+        Ok(tasks) => tasks,
+        Err(_) => return false,
+    };
+
+    for task in tasks {
+        let stat = task.and_then(|task| std::fs::read_to_string(task.path().join("stat")));
+        // the state follows the command name, which is in
+        // parentheses and may contain anything
+        let state = stat
+            .ok()
+            .and_then(|stat| stat.rsplit(')').next()?.trim_start().chars().next());
+        if state != Some('T') {
+            return false;
+        }
+    }
+
+    true
+}
+
+fn connect(port: u16) -> nats::Connection {
+    try_connect(port).unwrap()
+}
+
+fn try_connect(port: u16) -> io::Result<nats::Connection> {
+    nats::connect(&format!("localhost:{}", port))
+}
+
+/// Returns every undirected link between the two groups.
+fn links_between(a: &[usize], b: &[usize]) -> Vec<(usize, usize)> {
+    a.iter()
+        .flat_map(|x| b.iter().map(move |y| (*x, *y)))
+        .collect()
+}
+
+/// The ports that a server listens on. They are claimed for
+/// every run, so that concurrent runs don't collide.
+#[derive(Debug, Clone, Copy)]
+struct Ports {
+    client: u16,
+    // monitoring endpoints
+    http: u16,
+    cluster: u16,
+    // gateways from other clusters
+    gateway: u16,
+}
+
+impl From<&[u16]> for Ports {
+    fn from(ports: &[u16]) -> Ports {
+        Ports {
+            client: ports[0],
+            http: ports[1],
+            cluster: ports[2],
+            gateway: ports[3],
+        }
     }
 }
 
-/// Starts a local NATS server that gets killed on drop.
-fn server<P: AsRef<Path>>(path: P, idx: u16) -> Server {
-    let port = idx + 44000;
-    let storage_dir = format!("jetstream_test_{}", idx);
+/// Creates a directory for the configs, logs and storage
+/// of one run, unique to this process and run.
+fn run_dir() -> io::Result<PathBuf> {
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = std::env::temp_dir().join(format!(
+        "exercise_{}_{}",
+        std::process::id(),
+        RUNS.fetch_add(1, Ordering::SeqCst)
+    ));
+
+    // left over from an earlier process with the same pid
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+
+    Ok(dir)
+}
+
+/// The `server_name` in the generated config of a server.
+fn server_name(idx: usize) -> String {
+    format!("S{}", idx)
+}
+
+/// The name of the cluster, and of its gateway.
+fn cluster_name(idx: usize) -> String {
+    format!("C{}", idx)
+}
+
+/// Maps a name returned by `server_name` back to the
+/// server's index.
+fn server_index(name: &str) -> Option<usize> {
+    name.strip_prefix('S')?.parse().ok()
+}
+
+/// Starts a local NATS server with the given config file
+/// that gets killed on drop. Its storage and the output of
+/// each incarnation live in `run_dir`.
+fn server<P: AsRef<Path>>(
+    path: P,
+    idx: usize,
+    ports: Ports,
+    run_dir: &Path,
+    conf: PathBuf,
+) -> Server {
+    let storage_dir = run_dir.join(format!("jetstream_{}", idx));
     let _ = std::fs::remove_dir_all(&storage_dir);
 
-    let supercluster_conf = format!("confs/supercluster_{}.conf", idx);
-
-    let child = Command::new(path.as_ref())
-        .args(&["--port", &port.to_string()])
-        .arg("-js")
-        .args(&["-sd", &storage_dir])
-        .args(&["-c", &supercluster_conf])
-        .arg("-V")
-        .arg("-D")
-        .spawn()
-        .expect("unable to spawn nats-server");
-
-    Server {
-        child: Some(child),
-        port,
+    let snapshot_dir = run_dir.join(format!("snapshot_{}", idx));
+    let _ = std::fs::remove_dir_all(&snapshot_dir);
+
+    let mut server = Server {
+        child: None,
+        port: ports.client,
+        http_port: ports.http,
         storage_dir,
+        snapshot_dir,
         path: path.as_ref().into(),
+        conf,
+        spawned_at: Instant::now(),
         idx,
-    }
+        run_dir: run_dir.into(),
+        logs: vec![],
+    };
+
+    server.respawn();
+
+    server
 }
 
 struct Consumer {
     inner: nats::jetstream::Consumer,
-    observed: HashMap<u64, u64>,
+    // every message this consumer received, in delivery order
+    deliveries: Vec<Delivery>,
+    // how many of the deliveries have been validated
+    validated: usize,
+    // how many messages this client has tried to publish
+    published: u64,
     id: usize,
-}
-
-// we record every sid:uuid pair, and
-// ensure that consumers never observe
-// different uuid's for the same stream id.
-#[derive(Default, Debug)]
-struct DurabilityModel {
-    observed: HashMap<u64, u64>,
+    // the server that this client is connected to
+    server: usize,
 }
 
 const USAGE: &str = "
 Usage: exercise [--path=</path/to/nats-server>]
 
 Options:
-    --path=<p>      Path to nats-server binary [default: nats-server].
-    --seed=<#>      Seed for replaying faults [default: None].
-    --clients=<#>   Number of concurrent clients [default: 3].
-    --servers=<#>   Number of cluster servers [default: 3].
-    --steps=<#>     Number of steps to take [default: 10000].
-    --replicas=<#>  Number of replicas for the JetStream test stream [default: 1].
-    --no-kill       Do not restart servers, just pause/resume them [default: unset].
-    --burn-in       Ignore steps and run tests until we crash [default: unset].
+    --path=<p>                   Path to nats-server binary [default: nats-server].
+    --seed=<#>                   Seed for replaying faults [default: None].
+    --clients=<#>                Number of concurrent clients [default: 3].
+    --servers=<#>                Number of cluster servers [default: 3].
+    --clusters=<#>               Number of clusters that the servers are split into, connected by gateways [default: 1].
+    --servers-per-cluster=<#>    Number of servers in each cluster, overriding --servers [default: unset].
+    --stream-cluster=<#>         Index of the cluster that hosts the stream's replicas [default: chosen by the seed].
+    --steps=<#>                  Number of steps to take [default: 10000].
+    --replicas=<#>               Number of replicas for the JetStream test stream [default: 1].
+    --no-kill                    Do not restart servers, just pause/resume them [default: unset].
+    --burn-in                    Ignore steps and run tests until we crash [default: unset].
+    --link-delay=<ms>            Delay added to degraded links [default: 100].
+    --link-jitter=<ms>           Random jitter added to degraded links [default: 50].
+    --link-bandwidth=<#>         Bytes per second allowed on degraded links [default: unlimited].
+    --link-resets=<#>            Chance in 1000 of resetting a degraded link per chunk [default: 1].
+    --profile=<p>                Built-in profile name or profile file with op weights [default: default].
+    --weights=<w>                Override weights, like `restart:0,pause:x2` [default: unset].
+    --shutdown-deadline=<s>      Seconds a gracefully stopped server has to exit [default: 30].
+    --target=<t>                 Servers to aim faults at, see below [default: random].
+    --max-faulty=<#>             Most servers down at once, or `quorum` for (replicas - 1) / 2 [default: unlimited].
+    --availability-deadline=<s>  Seconds the stream may be unavailable with its quorum intact [default: 30].
+    --payload-padding=<#>        Bytes of padding added to every published message [default: 0].
+    --recovery-grace=<s>         Seconds every fault must stay healed before --recovery-deadline starts [default: 5].
+    --recovery-deadline=<s>      Seconds after the grace period that publishes and consumes must succeed again [default: 60].
+    --log-patterns=<p>           Comma-separated extra patterns to report from server logs [default: unset].
+    --keep-run-dir               Keep the run directory of a run that passes without findings [default: unset].
+    --history=<file>             Record every operation and fault to a newline-delimited JSON file [default: unset].
+    --trace=<file>               Record the schedule of decisions for --replay [default: exercise_<seed>.trace in the run directory].
+    --replay=<file>              Replay a recorded schedule, with its recorded options unless overridden [default: unset].
+    --shrink=<file>              Find the smallest part of a recorded schedule that still fails the same way [default: unset].
+    --shrink-runs=<#>            Replays of each candidate schedule while shrinking, for flaky failures [default: 1].
 ";
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Args {
     path: PathBuf,
     seed: u64,
     clients: u8,
     servers: u8,
+    clusters: usize,
+    servers_per_cluster: Option<u8>,
+    stream_cluster: Option<usize>,
     pub steps: u64,
     num_replicas: usize,
     no_kill: bool,
     pub burn_in: bool,
+    link_delay: u64,
+    link_jitter: u64,
+    link_bandwidth: Option<u64>,
+    link_resets: u64,
+    profile: Profile,
+    shutdown_deadline: u64,
+    target: Target,
+    max_faulty: FaultBudget,
+    availability_deadline: u64,
+    payload_padding: usize,
+    recovery_grace: u64,
+    recovery_deadline: u64,
+    log_patterns: Vec<String>,
+    keep_run_dir: bool,
+    history: Option<PathBuf>,
+    trace: Option<PathBuf>,
+    replay: Option<PathBuf>,
+    pub shrink: Option<PathBuf>,
+    shrink_runs: u32,
+    // the options that a replay of this run needs
+    recorded: Vec<String>,
     start_time: std::time::Instant,
 }
 
+/// How many servers may be paused, killed or
+/// partitioned away at the same time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultBudget {
+    Unlimited,
+    Servers(usize),
+    /// as many as the stream can lose while keeping a quorum
+    Quorum,
+}
+
+impl FaultBudget {
+    fn limit(self, replicas: usize) -> usize {
+        match self {
+            FaultBudget::Unlimited => usize::MAX,
+            FaultBudget::Servers(n) => n,
+            FaultBudget::Quorum => replicas.saturating_sub(1) / 2,
+        }
+    }
+}
+
+impl FromStr for FaultBudget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FaultBudget, String> {
+        match s {
+            "unlimited" => Ok(FaultBudget::Unlimited),
+            "quorum" => Ok(FaultBudget::Quorum),
+            n => n
+                .parse()
+                .map(FaultBudget::Servers)
+                .map_err(|e| format!("invalid fault budget {:?}: {}", n, e)),
+        }
+    }
+}
+
+/// Which servers process-level faults are aimed at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    /// any server
+    Random,
+    /// the leader of the test stream
+    Leader,
+    /// a server that hosts the test stream but doesn't lead it
+    Follower,
+    /// the leader of the JetStream meta group
+    MetaLeader,
+    /// one of the above, chosen anew for every fault
+    Mixed,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Target, String> {
+        match s {
+            "random" => Ok(Target::Random),
+            "leader" => Ok(Target::Leader),
+            "follower" => Ok(Target::Follower),
+            "meta-leader" => Ok(Target::MetaLeader),
+            "mixed" => Ok(Target::Mixed),
+            other => Err(format!("unknown target {:?}", other)),
+        }
+    }
+}
+
 impl Default for Args {
     fn default() -> Args {
         Args {
@@ -326,10 +2369,33 @@ impl Default for Args {
             seed: rand::thread_rng().gen(),
             clients: 3,
             servers: 3,
+            clusters: 1,
+            servers_per_cluster: None,
+            stream_cluster: None,
             steps: 10000,
             num_replicas: 1,
             no_kill: false,
             burn_in: false,
+            link_delay: 100,
+            link_jitter: 50,
+            link_bandwidth: None,
+            link_resets: 1,
+            profile: Profile::default(),
+            shutdown_deadline: 30,
+            target: Target::Random,
+            max_faulty: FaultBudget::Unlimited,
+            availability_deadline: 30,
+            payload_padding: 0,
+            recovery_grace: 5,
+            recovery_deadline: 60,
+            log_patterns: vec![],
+            keep_run_dir: false,
+            history: None,
+            trace: None,
+            replay: None,
+            shrink: None,
+            shrink_runs: 1,
+            recorded: vec![],
             start_time: std::time::Instant::now(),
         }
     }
@@ -346,21 +2412,151 @@ where
 
 impl Args {
     pub fn parse() -> Args {
+        Args::parse_from(std::env::args().skip(1))
+    }
+
+    /// Parses options given in the same form as on the
+    /// command line, panicking with the usage on bad ones.
+    pub fn parse_from<I: IntoIterator<Item = String>>(raw_args: I) -> Args {
         let mut args = Args::default();
-        for raw_arg in std::env::args().skip(1) {
+        let mut weights: Option<String> = None;
+
+        let mut raw_args: Vec<String> = raw_args.into_iter().collect();
+        let mut replay_steps = None;
+
+        let replayed = raw_args.iter().find_map(|arg| {
+            arg.strip_prefix("--replay=")
+                .or_else(|| arg.strip_prefix("--shrink="))
+        });
+
+        if let Some(path) = replayed {
+            let trace = trace::load(path).expect("unable to load replay trace");
+            replay_steps = Some(trace.decisions.len() as u64);
+            // options given alongside --replay override the recorded ones
+            raw_args = trace.args.into_iter().chain(raw_args).collect();
+        }
+
+        for raw_arg in &raw_args {
             let mut splits = raw_arg[2..].split('=');
             match splits.next().unwrap() {
                 "path" => args.path = parse(&mut splits),
                 "seed" => args.seed = parse(&mut splits),
                 "clients" => args.clients = parse(&mut splits),
                 "servers" => args.servers = parse(&mut splits),
+                "clusters" => args.clusters = parse(&mut splits),
+                "servers-per-cluster" => args.servers_per_cluster = Some(parse(&mut splits)),
+                "stream-cluster" => args.stream_cluster = Some(parse(&mut splits)),
                 "steps" => args.steps = parse(&mut splits),
                 "replicas" => args.num_replicas = parse(&mut splits),
                 "no-kill" => args.no_kill = true,
                 "burn-in" => args.burn_in = true,
+                "link-delay" => args.link_delay = parse(&mut splits),
+                "link-jitter" => args.link_jitter = parse(&mut splits),
+                "link-bandwidth" => args.link_bandwidth = Some(parse(&mut splits)),
+                "link-resets" => args.link_resets = parse(&mut splits),
+                "profile" => args.profile = parse(&mut splits),
+                "weights" => weights = Some(parse(&mut splits)),
+                "shutdown-deadline" => args.shutdown_deadline = parse(&mut splits),
+                "target" => args.target = parse(&mut splits),
+                "max-faulty" => args.max_faulty = parse(&mut splits),
+                "availability-deadline" => args.availability_deadline = parse(&mut splits),
+                "payload-padding" => args.payload_padding = parse(&mut splits),
+                "recovery-grace" => args.recovery_grace = parse(&mut splits),
+                "recovery-deadline" => args.recovery_deadline = parse(&mut splits),
+                "log-patterns" => {
+                    let patterns: String = parse(&mut splits);
+                    // an empty pattern would match every line
+                    args.log_patterns = patterns
+                        .split(',')
+                        .filter(|pattern| !pattern.is_empty())
+                        .map(String::from)
+                        .collect();
+                }
+                "keep-run-dir" => args.keep_run_dir = true,
+                "history" => args.history = Some(parse(&mut splits)),
+                "trace" => args.trace = Some(parse(&mut splits)),
+                "replay" => args.replay = Some(parse(&mut splits)),
+                "shrink" => args.shrink = Some(parse(&mut splits)),
+                "shrink-runs" => args.shrink_runs = parse(&mut splits),
                 other => panic!("unknown option: {}, {}", other, USAGE),
             }
         }
+        if let Some(weights) = weights {
+            // applied last so that they override whichever profile was chosen
+            args.profile.apply_overrides(&weights).expect(USAGE);
+        }
+        assert!(
+            args.profile.total() > 0,
+            "every operation is disabled, {}",
+            USAGE
+        );
+
+        if let Some(per_cluster) = args.servers_per_cluster {
+            args.servers = (per_cluster as usize)
+                .checked_mul(args.clusters)
+                .and_then(|servers| u8::try_from(servers).ok())
+                .unwrap_or_else(|| {
+                    panic!(
+                        "at most {} servers are supported in total, {}",
+                        u8::MAX,
+                        USAGE
+                    )
+                });
+        }
+        assert!(
+            args.clusters > 0 && (args.servers as usize).is_multiple_of(args.clusters),
+            "--servers must be a multiple of --clusters, {}",
+            USAGE
+        );
+        assert!(
+            args.num_replicas <= args.servers_per_cluster(),
+            "the stream's replicas all live in one cluster, so it can't have \
+            more --replicas than servers per cluster, {}",
+            USAGE
+        );
+        assert!(
+            args.stream_cluster
+                .is_none_or(|cluster| cluster < args.clusters),
+            "--stream-cluster must be below --clusters, {}",
+            USAGE
+        );
+
+        if let Some(steps) = replay_steps {
+            args.steps = steps;
+            args.burn_in = false;
+        }
+
+        // a replay must not overwrite the files of the run it replays
+        args.recorded = raw_args
+            .into_iter()
+            .filter(|arg| {
+                !["--history=", "--trace=", "--replay=", "--shrink"]
+                    .iter()
+                    .any(|option| arg.starts_with(option))
+            })
+            .chain(Some(format!("--seed={}", args.seed)))
+            .collect();
+
         args
     }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The cluster that the stream is placed in.
+    fn stream_cluster(&self) -> usize {
+        self.stream_cluster
+            .unwrap_or((self.seed % self.clusters as u64) as usize)
+    }
+
+    fn servers_per_cluster(&self) -> usize {
+        self.servers as usize / self.clusters
+    }
+
+    /// The cluster that the server belongs to. Servers are
+    /// numbered consecutively within each cluster.
+    fn cluster_of(&self, server: usize) -> usize {
+        server / self.servers_per_cluster()
+    }
 }
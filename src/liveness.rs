@@ -0,0 +1,100 @@
+use std::time::{Duration, Instant};
+
+/// Tracks whether publishes and consumes keep succeeding
+/// for as long as some condition holds, such as the
+/// stream's quorum being intact. Nothing counts as failing
+/// until the condition has held for the grace period.
+#[derive(Debug)]
+pub(crate) struct Availability {
+    grace: Duration,
+    deadline: Duration,
+    // when the condition started holding, if it currently does
+    since: Option<Instant>,
+    last_publish: Option<Instant>,
+    last_consume: Option<Instant>,
+    // since when acknowledged messages have been waiting to be
+    // consumed, if any are
+    backlog_since: Option<Instant>,
+}
+
+impl Availability {
+    pub(crate) fn new(grace: Duration, deadline: Duration) -> Availability {
+        Availability {
+            grace,
+            deadline,
+            since: None,
+            last_publish: None,
+            last_consume: None,
+            backlog_since: None,
+        }
+    }
+
+    /// Called every step with whether the condition holds.
+    pub(crate) fn update(&mut self, holds: bool) {
+        if holds {
+            self.since.get_or_insert_with(Instant::now);
+        } else {
+            self.since = None;
+        }
+    }
+
+    /// Called every step with whether any acknowledged message
+    /// is still waiting to be consumed. Consumes only count as
+    /// failing while there is something to consume.
+    pub(crate) fn backlog(&mut self, pending: bool) {
+        if pending {
+            self.backlog_since.get_or_insert_with(Instant::now);
+        } else {
+            self.backlog_since = None;
+        }
+    }
+
+    pub(crate) fn published(&mut self) {
+        self.last_publish = Some(Instant::now());
+    }
+
+    pub(crate) fn consumed(&mut self) {
+        self.last_consume = Some(Instant::now());
+    }
+
+    /// How long publishes have been failing, counting only
+    /// the time that the condition held after the grace period.
+    pub(crate) fn publishes_failing_for(&self) -> Option<Duration> {
+        self.failing_for(self.last_publish)
+    }
+
+    /// Describes what has been unavailable for longer than
+    /// the deadline while the condition held.
+    pub(crate) fn check(&self) -> Option<String> {
+        // a backlog that appeared after the last consume
+        // started waiting when it appeared
+        let consumes_failing = self.backlog_since.and_then(|backlog| {
+            let from = self.last_consume.map_or(backlog, |last| last.max(backlog));
+            self.failing_for(Some(from))
+        });
+
+        for (what, failing) in &[
+            ("publish", self.publishes_failing_for()),
+            ("consume", consumes_failing),
+        ] {
+            if let Some(failing) = *failing {
+                if failing > self.deadline {
+                    return Some(format!(
+                        "no {} succeeded for {:?}, the deadline is {:?}",
+                        what, failing, self.deadline
+                    ));
+                }
+            }
+        }
+        None
+    }
+
+    fn failing_for(&self, last_success: Option<Instant>) -> Option<Duration> {
+        let since = self.since? + self.grace;
+        if since > Instant::now() {
+            return None;
+        }
+        let from = last_success.map_or(since, |last| last.max(since));
+        Some(from.elapsed())
+    }
+}
@@ -0,0 +1,94 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+/// Patterns that always mean that a server hit a bug.
+pub(crate) const FATAL_PATTERNS: &[&str] = &["panic:", "DATA RACE", "fatal error"];
+
+/// The lines of a log that matched one pattern.
+#[derive(Debug)]
+pub(crate) struct Match {
+    pub pattern: String,
+    pub count: usize,
+    pub first_line_number: usize,
+    pub first_line: String,
+}
+
+/// Finds the lines of a server log that contain any of
+/// the patterns, grouped by pattern in the order given.
+pub(crate) fn scan<P: AsRef<Path>>(path: P, patterns: &[String]) -> io::Result<Vec<Match>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut matches: Vec<Match> = vec![];
+
+    for (idx, line) in reader.split(b'\n').enumerate() {
+        // debug output may contain arbitrary message payloads
+        let line = String::from_utf8_lossy(&line?).into_owned();
+
+        for pattern in patterns {
+            if !line.contains(pattern.as_str()) {
+                continue;
+            }
+
+            match matches.iter_mut().find(|m| &m.pattern == pattern) {
+                Some(m) => m.count += 1,
+                None => matches.push(Match {
+                    pattern: pattern.clone(),
+                    count: 1,
+                    first_line_number: idx + 1,
+                    first_line: line.trim_end().to_string(),
+                }),
+            }
+        }
+    }
+
+    matches.sort_by_key(|m| patterns.iter().position(|p| p == &m.pattern));
+
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan_log(name: &str, content: &[u8], patterns: &[&str]) -> Vec<Match> {
+        let path = std::env::temp_dir().join(format!("{}_{}.log", name, std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+        let matches = scan(&path, &patterns).unwrap();
+        let _ = std::fs::remove_file(&path);
+        matches
+    }
+
+    #[test]
+    fn matches_are_counted_from_the_first_line() {
+        let log = b"[INF] starting\n\
+            [WRN] slow consumer\n\
+            [INF] ok\n\
+            [WRN] slow consumer again\r\n\
+            panic: runtime error\n";
+
+        let matches = scan_log("counted", log, &["panic:", "slow consumer", "DATA RACE"]);
+
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].pattern, "panic:");
+        assert_eq!(matches[0].count, 1);
+        assert_eq!(matches[0].first_line_number, 5);
+        assert_eq!(matches[1].pattern, "slow consumer");
+        assert_eq!(matches[1].count, 2);
+        assert_eq!(matches[1].first_line_number, 2);
+        assert_eq!(matches[1].first_line, "[WRN] slow consumer");
+    }
+
+    #[test]
+    fn non_utf8_lines_are_scanned() {
+        let log = b"payload \xff\xfe\n\xc3 fatal error: out of memory\n";
+
+        let matches = scan_log("lossy", log, &["fatal error"]);
+
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].first_line_number, 2);
+        assert!(matches[0]
+            .first_line
+            .ends_with("fatal error: out of memory"));
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::payload::MessageId;
+
+/// A message as a consumer received it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Delivery {
+    #[serde(rename = "stream_seq")]
+    pub seq: u64,
+    pub value: MessageId,
+    /// when the message was published, per the publisher's clock
+    pub timestamp: u64,
+    /// whether the server delivered this message before
+    #[serde(default)]
+    pub redelivered: bool,
+}
+
+/// A guarantee that the cluster broke.
+#[derive(Debug)]
+pub struct Violation {
+    pub kind: &'static str,
+    /// the check that found it, which tells violations of
+    /// the same kind apart
+    pub check: &'static str,
+    pub description: String,
+}
+
+// we record every sid:uuid pair, and
+// ensure that consumers never observe
+// different uuid's for the same stream id.
+// we also record the stream id that each
+// acknowledged uuid was assigned, so that
+// we can tell when acked writes go missing.
+#[derive(Default, Debug)]
+pub(crate) struct DurabilityModel {
+    pub observed: HashMap<u64, MessageId>,
+    pub acked: HashMap<MessageId, u64>,
+    // the highest stream sequence delivered to each consumer
+    highest: HashMap<usize, u64>,
+    // the latest acked publish of each publisher, and
+    // the stream sequence it was acked with
+    last_acked: HashMap<u64, (MessageId, u64)>,
+}
+
+impl DurabilityModel {
+    /// Records that a publish was acknowledged with the
+    /// given stream sequence.
+    pub(crate) fn acked(&mut self, message: MessageId, seq: u64) -> Result<(), Violation> {
+        // publishes are synchronous, so each one that is acked
+        // must land after the previous acked one from its publisher
+        let previous = self.last_acked.insert(message.publisher, (message, seq));
+        if let Some((previous_message, previous_seq)) = previous {
+            if seq <= previous_seq {
+                return Err(Violation {
+                    kind: "Ordering",
+                    check: "ack-order",
+                    description: format!(
+                        "A publisher's acknowledged messages were stored out of order.
+                            publisher: {}
+                            earlier message {} acked at stream sequence {}
+                            later message {} acked at stream sequence {}",
+                        message.publisher, previous_message, previous_seq, message, seq
+                    ),
+                });
+            }
+        }
+
+        self.acked.insert(message, seq);
+
+        Ok(())
+    }
+
+    /// The highest stream sequence that any publish was
+    /// acknowledged with.
+    pub(crate) fn last_acked_seq(&self) -> u64 {
+        self.last_acked
+            .values()
+            .map(|(_, seq)| *seq)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Records a message that a consumer received.
+    pub(crate) fn delivered(
+        &mut self,
+        consumer: usize,
+        delivery: &Delivery,
+    ) -> Result<(), Violation> {
+        // a push consumer delivers the stream in order, and
+        // may only go back to redeliver unacked messages
+        let highest = self.highest(consumer);
+        if !delivery.redelivered && delivery.seq <= highest {
+            return Err(Violation {
+                kind: "Ordering",
+                check: "delivery-order",
+                description: format!(
+                    "A consumer received a stream sequence out of order \
+                    without it being a redelivery.
+                        consumer: {}
+                        stream sequence: {}
+                        message: {}, published at {} ms since the epoch
+                        highest previously delivered sequence: {}",
+                    consumer, delivery.seq, delivery.value, delivery.timestamp, highest,
+                ),
+            });
+        }
+        self.highest.insert(consumer, highest.max(delivery.seq));
+
+        let (seq, value) = (delivery.seq, delivery.value);
+        if let Some(old_value) = self.observed.insert(seq, value) {
+            if value != old_value {
+                return Err(Violation {
+                    kind: "Correctness",
+                    check: "conflicting-values",
+                    description: format!(
+                        "Consumers received different values for the same \
+                        stream sequence.
+                            stream sequence: {}
+                            first observed value: {}
+                            second observed value: {}",
+                        seq, old_value, value,
+                    ),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The highest stream sequence delivered to the consumer.
+    pub(crate) fn highest(&self, consumer: usize) -> u64 {
+        self.highest.get(&consumer).copied().unwrap_or(0)
+    }
+
+    /// Returns the sequences up to `last_seq` that no
+    /// consumer has observed yet.
+    pub(crate) fn gaps(&self, last_seq: u64) -> Vec<u64> {
+        (1..=last_seq)
+            .filter(|seq| !self.observed.contains_key(seq))
+            .collect()
+    }
+
+    /// Checks that the consumers observed every sequence up to
+    /// `last_seq`, and every acked publish at its acked sequence.
+    pub(crate) fn check_drained(&self, last_seq: u64) -> Result<(), Violation> {
+        let gaps = self.gaps(last_seq);
+
+        if !gaps.is_empty() {
+            return Err(Violation {
+                kind: "Correctness",
+                check: "unobserved-sequences",
+                description: format!(
+                    "Stream sequences were never observed after draining.
+                        stream last sequence: {}
+                        unobserved stream sequences: {} (first few: {:?})",
+                    last_seq,
+                    gaps.len(),
+                    &gaps[..gaps.len().min(20)],
+                ),
+            });
+        }
+
+        let observed_at: HashMap<MessageId, u64> = self
+            .observed
+            .iter()
+            .map(|(seq, value)| (*value, *seq))
+            .collect();
+
+        let mut lost = vec![];
+        let mut moved = vec![];
+
+        for (value, acked_seq) in &self.acked {
+            match observed_at.get(value) {
+                None => lost.push((*value, *acked_seq)),
+                Some(seq) if seq != acked_seq => moved.push((*value, *acked_seq, *seq)),
+                Some(_) => {}
+            }
+        }
+
+        if lost.is_empty() && moved.is_empty() {
+            return Ok(());
+        }
+
+        lost.sort_unstable_by_key(|(_, seq)| *seq);
+        moved.sort_unstable_by_key(|(_, seq, _)| *seq);
+
+        Err(Violation {
+            kind: "Correctness",
+            check: "lost-acks",
+            description: format!(
+                "Acknowledged publishes were lost or moved.
+                    acknowledged publishes: {}
+                    lost (value, acked stream sequence): {:?}
+                    moved (value, acked stream sequence, observed stream sequence): {:?}",
+                self.acked.len(),
+                lost,
+                moved
+            ),
+        })
+    }
+}
+
+/// Counts deliveries of sequences that were already delivered
+/// before, and deliveries that the server marked as redelivered.
+pub(crate) fn duplicates_and_redeliveries(deliveries: &[Delivery]) -> (usize, usize) {
+    let mut seen = HashSet::new();
+    let duplicates = deliveries.iter().filter(|d| !seen.insert(d.seq)).count();
+    let redeliveries = deliveries.iter().filter(|d| d.redelivered).count();
+    (duplicates, redeliveries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(publisher: u64, seq: u64) -> MessageId {
+        MessageId { publisher, seq }
+    }
+
+    fn delivery(seq: u64, value: MessageId, redelivered: bool) -> Delivery {
+        Delivery {
+            seq,
+            value,
+            timestamp: 0,
+            redelivered,
+        }
+    }
+
+    #[test]
+    fn acks_must_follow_the_publishers_previous_ack() {
+        let mut model = DurabilityModel::default();
+        model.acked(id(0, 0), 1).unwrap();
+        model.acked(id(1, 0), 2).unwrap();
+        model.acked(id(0, 1), 3).unwrap();
+        // publishers are independent of each other
+        model.acked(id(1, 1), 4).unwrap();
+        assert_eq!(model.last_acked_seq(), 4);
+
+        let violation = model.acked(id(0, 2), 3).unwrap_err();
+        assert_eq!(violation.kind, "Ordering");
+    }
+
+    #[test]
+    fn deliveries_may_only_go_back_when_redelivered() {
+        let mut model = DurabilityModel::default();
+        model.delivered(0, &delivery(1, id(0, 0), false)).unwrap();
+        model.delivered(0, &delivery(2, id(0, 1), false)).unwrap();
+        model.delivered(0, &delivery(1, id(0, 0), true)).unwrap();
+        // every consumer reads the stream on its own
+        model.delivered(1, &delivery(1, id(0, 0), false)).unwrap();
+        assert_eq!(model.highest(0), 2);
+        assert_eq!(model.highest(1), 1);
+
+        let violation = model
+            .delivered(0, &delivery(2, id(0, 1), false))
+            .unwrap_err();
+        assert_eq!(violation.kind, "Ordering");
+    }
+
+    #[test]
+    fn conflicting_values_are_caught() {
+        let mut model = DurabilityModel::default();
+        model.delivered(0, &delivery(1, id(0, 0), false)).unwrap();
+
+        let violation = model
+            .delivered(1, &delivery(1, id(1, 0), false))
+            .unwrap_err();
+        assert_eq!(violation.kind, "Correctness");
+        assert_eq!(violation.check, "conflicting-values");
+    }
+
+    #[test]
+    fn draining_catches_gaps_and_lost_or_moved_acks() {
+        let mut model = DurabilityModel::default();
+        model.acked(id(0, 0), 1).unwrap();
+        model.delivered(0, &delivery(1, id(0, 0), false)).unwrap();
+        // unacked publishes may show up without an ack
+        model.delivered(0, &delivery(2, id(1, 0), false)).unwrap();
+        assert!(model.check_drained(2).is_ok());
+
+        let gap = model.check_drained(3).unwrap_err();
+        assert_eq!(gap.kind, "Correctness");
+        assert_eq!(gap.check, "unobserved-sequences");
+        assert_eq!(model.gaps(3), vec![3]);
+
+        let mut lost = DurabilityModel::default();
+        lost.acked(id(0, 0), 1).unwrap();
+        lost.acked(id(0, 1), 2).unwrap();
+        lost.delivered(0, &delivery(1, id(0, 0), false)).unwrap();
+        lost.delivered(0, &delivery(2, id(1, 0), false)).unwrap();
+        let violation = lost.check_drained(2).unwrap_err();
+        assert_eq!(violation.kind, "Correctness");
+        assert_eq!(violation.check, "lost-acks");
+
+        let mut moved = DurabilityModel::default();
+        moved.acked(id(0, 0), 1).unwrap();
+        moved.delivered(0, &delivery(1, id(1, 0), false)).unwrap();
+        moved.delivered(0, &delivery(2, id(0, 0), false)).unwrap();
+        let violation = moved.check_drained(2).unwrap_err();
+        assert_eq!(violation.kind, "Correctness");
+    }
+
+    #[test]
+    fn duplicates_and_redeliveries_are_counted_apart() {
+        let deliveries = [
+            delivery(1, id(0, 0), false),
+            delivery(2, id(0, 1), false),
+            delivery(2, id(0, 1), true),
+            delivery(3, id(0, 2), true),
+        ];
+        assert_eq!(duplicates_and_redeliveries(&deliveries), (1, 2));
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+// paused servers never answer, so don't wait on them for long
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+// creating a replicated stream waits for its raft group
+const CREATE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The part of a stream's info that describes its raft group.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct StreamCluster {
+    pub leader: Option<String>,
+    #[serde(default)]
+    pub replicas: Vec<Peer>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Peer {
+    pub name: String,
+    #[serde(default)]
+    pub current: bool,
+    #[serde(default)]
+    pub offline: bool,
+}
+
+/// How far a stream has gotten.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct StreamState {
+    pub last_seq: u64,
+    #[serde(default)]
+    pub first_seq: u64,
+    #[serde(default)]
+    pub messages: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct StreamInfo {
+    pub cluster: StreamCluster,
+    pub state: StreamState,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamInfoResponse {
+    cluster: Option<StreamCluster>,
+    state: Option<StreamState>,
+    error: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Varz {
+    jetstream: Option<JetStreamVarz>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JetStreamVarz {
+    // only present when JetStream is enabled
+    config: Option<serde_json::Value>,
+    meta: Option<StreamCluster>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jsz {
+    #[serde(default)]
+    account_details: Vec<AccountDetail>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AccountDetail {
+    #[serde(default)]
+    stream_detail: Vec<StreamDetail>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamDetail {
+    name: String,
+    state: StreamState,
+}
+
+#[derive(Debug, Deserialize)]
+struct Routez {
+    #[serde(default)]
+    routes: Vec<Route>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Route {
+    remote_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Gatewayz {
+    #[serde(default)]
+    outbound_gateways: HashMap<String, serde_json::Value>,
+}
+
+/// Creates the stream through the JetStream API, with its
+/// replicas placed in the given cluster. A stream's raft
+/// group can't span clusters, so all of them go there.
+pub(crate) fn create_stream(
+    nc: &nats::Connection,
+    stream: &str,
+    replicas: usize,
+    cluster: &str,
+) -> io::Result<()> {
+    let config = serde_json::json!({
+        "name": stream,
+        "subjects": [stream],
+        "retention": "limits",
+        "storage": "file",
+        "num_replicas": replicas,
+        "placement": { "cluster": cluster },
+    });
+
+    let subject = format!("$JS.API.STREAM.CREATE.{}", stream);
+    let msg = nc.request_timeout(&subject, config.to_string(), CREATE_TIMEOUT)?;
+
+    let response: StreamInfoResponse = serde_json::from_slice(&msg.data)?;
+
+    match response.error {
+        Some(error) => Err(io::Error::other(error.to_string())),
+        None => Ok(()),
+    }
+}
+
+/// Asks the JetStream API which servers host the stream.
+pub(crate) fn stream_cluster(nc: &nats::Connection, stream: &str) -> io::Result<StreamCluster> {
+    Ok(stream_info(nc, stream)?.cluster)
+}
+
+/// Asks the JetStream API for the stream's raft group and
+/// its last sequence.
+pub(crate) fn stream_info(nc: &nats::Connection, stream: &str) -> io::Result<StreamInfo> {
+    let subject = format!("$JS.API.STREAM.INFO.{}", stream);
+    let msg = nc.request_timeout(&subject, b"", TIMEOUT)?;
+
+    let response: StreamInfoResponse = serde_json::from_slice(&msg.data)?;
+
+    if let Some(error) = response.error {
+        return Err(io::Error::other(error.to_string()));
+    }
+
+    Ok(StreamInfo {
+        cluster: response.cluster.unwrap_or_default(),
+        state: response.state.unwrap_or_default(),
+    })
+}
+
+/// Asks a server's monitoring endpoint which server
+/// currently leads the JetStream meta group.
+pub(crate) fn meta_leader(host: &str, http_port: u16) -> io::Result<Option<String>> {
+    let varz: Varz = serde_json::from_str(&http_get(host, http_port, "/varz")?)?;
+
+    Ok(varz
+        .jetstream
+        .and_then(|jetstream| jetstream.meta)
+        .and_then(|meta| meta.leader))
+}
+
+/// The state of a stream as the server's own replica sees
+/// it, or `None` if the server doesn't host the stream.
+pub(crate) fn replica_state(
+    host: &str,
+    http_port: u16,
+    stream: &str,
+) -> io::Result<Option<StreamState>> {
+    let jsz: Jsz = serde_json::from_str(&http_get(
+        host,
+        http_port,
+        "/jsz?accounts=true&streams=true",
+    )?)?;
+
+    Ok(jsz
+        .account_details
+        .into_iter()
+        .flat_map(|account| account.stream_detail)
+        .find(|detail| detail.name == stream)
+        .map(|detail| detail.state))
+}
+
+/// Fails unless the server reports itself healthy. With
+/// `js_enabled_only`, JetStream only has to be enabled,
+/// rather than current with the rest of the cluster.
+pub(crate) fn healthz(host: &str, http_port: u16, js_enabled_only: bool) -> io::Result<()> {
+    let path = if js_enabled_only {
+        "/healthz?js-enabled-only=true"
+    } else {
+        "/healthz"
+    };
+    http_get(host, http_port, path).map(drop)
+}
+
+/// Whether the server runs with JetStream enabled.
+pub(crate) fn jetstream_enabled(host: &str, http_port: u16) -> io::Result<bool> {
+    let varz: Varz = serde_json::from_str(&http_get(host, http_port, "/varz")?)?;
+
+    Ok(varz
+        .jetstream
+        .is_some_and(|jetstream| jetstream.config.is_some()))
+}
+
+/// How many other servers a server has routes to.
+/// Servers may pool several routes to each peer, so they
+/// are counted by id.
+pub(crate) fn routed_servers(host: &str, http_port: u16) -> io::Result<usize> {
+    let routez: Routez = serde_json::from_str(&http_get(host, http_port, "/routez")?)?;
+    let ids: HashSet<String> = routez.routes.into_iter().map(|r| r.remote_id).collect();
+    Ok(ids.len())
+}
+
+/// The clusters that a server has outbound gateways to.
+pub(crate) fn gateway_clusters(host: &str, http_port: u16) -> io::Result<HashSet<String>> {
+    let gatewayz: Gatewayz = serde_json::from_str(&http_get(host, http_port, "/gatewayz")?)?;
+    Ok(gatewayz.outbound_gateways.into_keys().collect())
+}
+
+/// A minimal HTTP client for the monitoring port.
+pub(crate) fn http_get(host: &str, port: u16, path: &str) -> io::Result<String> {
+    let mut stream = TcpStream::connect((host, port))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, host
+    )?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let mut parts = response.splitn(2, "\r\n\r\n");
+    let head = parts.next().unwrap_or_default();
+    let body = parts.next().unwrap_or_default();
+
+    if !head.starts_with("HTTP/1.1 200") && !head.starts_with("HTTP/1.0 200") {
+        return Err(io::Error::other(format!(
+            "GET {} failed: {}",
+            path,
+            head.lines().next().unwrap_or_default()
+        )));
+    }
+
+    Ok(body.to_string())
+}
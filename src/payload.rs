@@ -0,0 +1,176 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+// publisher, sequence and timestamp, then the padding length
+const HEADER_LEN: usize = 8 + 8 + 8 + 4;
+const CHECKSUM_LEN: usize = 8;
+
+/// Identifies a message by who published it, and how many
+/// messages that publisher had attempted before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub(crate) struct MessageId {
+    pub publisher: u64,
+    pub seq: u64,
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.publisher, self.seq)
+    }
+}
+
+/// What the exerciser publishes into the stream.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Payload {
+    pub id: MessageId,
+    /// milliseconds since the unix epoch at publish time
+    pub timestamp: u64,
+    pub padding: usize,
+}
+
+impl Payload {
+    pub(crate) fn new(id: MessageId, padding: usize) -> Payload {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        Payload {
+            id,
+            timestamp,
+            padding,
+        }
+    }
+
+    /// Little endian publisher, sequence, timestamp and padding
+    /// length, followed by the padding and a checksum of
+    /// everything before it.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_LEN + self.padding + CHECKSUM_LEN);
+
+        data.extend_from_slice(&self.id.publisher.to_le_bytes());
+        data.extend_from_slice(&self.id.seq.to_le_bytes());
+        data.extend_from_slice(&self.timestamp.to_le_bytes());
+        data.extend_from_slice(&(self.padding as u32).to_le_bytes());
+
+        // derived from the id, so damaged padding can't go unnoticed
+        let fill = (self.id.publisher ^ self.id.seq) as u8;
+        data.resize(HEADER_LEN + self.padding, fill);
+
+        let checksum = checksum(&data);
+        data.extend_from_slice(&checksum.to_le_bytes());
+
+        data
+    }
+
+    /// Fails with `InvalidData` if the data is not a payload
+    /// that `encode` produced.
+    pub(crate) fn decode(data: &[u8]) -> io::Result<Payload> {
+        let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, reason);
+
+        if data.len() < HEADER_LEN + CHECKSUM_LEN {
+            return Err(invalid(format!(
+                "payload of {} bytes is too short",
+                data.len()
+            )));
+        }
+
+        let (body, tail) = data.split_at(data.len() - CHECKSUM_LEN);
+        let expected = u64::from_le_bytes(tail.try_into().unwrap());
+        let actual = checksum(body);
+        if expected != actual {
+            return Err(invalid(format!(
+                "payload checksum is {:#x} but its content sums to {:#x}",
+                expected, actual
+            )));
+        }
+
+        let word = |at: usize| u64::from_le_bytes(body[at..at + 8].try_into().unwrap());
+        let padding = u32::from_le_bytes(body[24..28].try_into().unwrap()) as usize;
+
+        if body.len() != HEADER_LEN + padding {
+            return Err(invalid(format!(
+                "payload claims {} bytes of padding but has {}",
+                padding,
+                body.len() - HEADER_LEN
+            )));
+        }
+
+        Ok(Payload {
+            id: MessageId {
+                publisher: word(0),
+                seq: word(8),
+            },
+            timestamp: word(16),
+            padding,
+        })
+    }
+}
+
+// 64-bit FNV-1a
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(padding: usize) -> Payload {
+        Payload::new(
+            MessageId {
+                publisher: 3,
+                seq: 42,
+            },
+            padding,
+        )
+    }
+
+    #[test]
+    fn encoded_payloads_decode() {
+        for padding in &[0, 1, 100] {
+            let payload = payload(*padding);
+            assert_eq!(Payload::decode(&payload.encode()).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn checksum_mismatches_are_invalid() {
+        let mut data = payload(16).encode();
+        for at in &[0, 20, HEADER_LEN + 3, data.len() - 1] {
+            data[*at] ^= 1;
+            let err = Payload::decode(&data).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains("checksum"), "{}", err);
+            data[*at] ^= 1;
+        }
+    }
+
+    #[test]
+    fn truncated_payloads_are_invalid() {
+        let data = payload(16).encode();
+        for len in &[0, HEADER_LEN, HEADER_LEN + CHECKSUM_LEN - 1, data.len() - 1] {
+            let err = Payload::decode(&data[..*len]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn wrong_padding_lengths_are_invalid() {
+        let mut data = payload(16).encode();
+        data.truncate(data.len() - CHECKSUM_LEN);
+        data[24..28].copy_from_slice(&17u32.to_le_bytes());
+        let checksum = checksum(&data);
+        data.extend_from_slice(&checksum.to_le_bytes());
+
+        let err = Payload::decode(&data).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("padding"), "{}", err);
+    }
+}
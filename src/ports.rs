@@ -0,0 +1,134 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::net::TcpListener;
+use std::sync::Mutex;
+
+use crate::LOCALHOST;
+
+// what Linux uses unless configured otherwise
+const DEFAULT_EPHEMERAL_RANGE: (u16, u16) = (32768, 60999);
+
+// below are the privileged ports
+const FIRST_UNPRIVILEGED_PORT: u16 = 1024;
+
+// ports that blocks of this process hold at the moment
+static CLAIMED: Mutex<BTreeSet<u16>> = Mutex::new(BTreeSet::new());
+
+/// Ports that a run's servers keep for the whole run, across
+/// restarts. They lie outside the kernel's ephemeral range,
+/// so neither `:0` binds like the proxies' nor outgoing
+/// connections are ever given one of them while a server is
+/// down. Other blocks of this process skip them until the
+/// block is dropped.
+#[derive(Debug)]
+pub(crate) struct PortBlock {
+    ports: Vec<u16>,
+}
+
+impl PortBlock {
+    /// Claims `n` ports that are free right now.
+    pub(crate) fn claim(n: usize) -> io::Result<PortBlock> {
+        let (low, high) = ephemeral_range();
+        let candidates: Vec<u16> = (FIRST_UNPRIVILEGED_PORT..=u16::MAX)
+            .filter(|port| *port < low || *port > high)
+            .collect();
+
+        if candidates.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "no ports outside the ephemeral range",
+            ));
+        }
+
+        // processes start looking in different places, so that
+        // concurrent ones rarely try to claim the same ports
+        let start = std::process::id() as usize * 97 % candidates.len();
+
+        let mut claimed = CLAIMED.lock().unwrap();
+        let ports: Vec<u16> = candidates[start..]
+            .iter()
+            .chain(&candidates[..start])
+            .filter(|port| !claimed.contains(port))
+            // anything else listening on it already
+            .filter(|port| TcpListener::bind((LOCALHOST, **port)).is_ok())
+            .take(n)
+            .copied()
+            .collect();
+
+        if ports.len() < n {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!(
+                    "only {} of {} ports outside the ephemeral range {}-{} are free",
+                    ports.len(),
+                    n,
+                    low,
+                    high
+                ),
+            ));
+        }
+
+        claimed.extend(&ports);
+
+        Ok(PortBlock { ports })
+    }
+
+    pub(crate) fn ports(&self) -> &[u16] {
+        &self.ports
+    }
+}
+
+impl Drop for PortBlock {
+    fn drop(&mut self) {
+        let mut claimed = CLAIMED.lock().unwrap();
+        for port in &self.ports {
+            claimed.remove(port);
+        }
+    }
+}
+
+/// The range that the kernel picks local ports from for `:0`
+/// binds and outgoing connections, inclusive.
+fn ephemeral_range() -> (u16, u16) {
+    let range = match fs::read_to_string("/proc/sys/net/ipv4/ip_local_port_range") {
+        Ok(range) => range,
+        Err(_) => return DEFAULT_EPHEMERAL_RANGE,
+    };
+
+    let bounds: Vec<u16> = range
+        .split_whitespace()
+        .filter_map(|bound| bound.parse().ok())
+        .collect();
+
+    match bounds[..] {
+        [low, high] if low <= high => (low, high),
+        _ => DEFAULT_EPHEMERAL_RANGE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_avoid_the_ephemeral_range_and_each_other() {
+        let (low, high) = ephemeral_range();
+
+        let first = PortBlock::claim(8).unwrap();
+        let second = PortBlock::claim(8).unwrap();
+
+        let ports: BTreeSet<u16> = first
+            .ports()
+            .iter()
+            .chain(second.ports())
+            .copied()
+            .collect();
+        assert_eq!(ports.len(), 16);
+        assert!(ports.iter().all(|port| *port < low || *port > high));
+
+        drop(first);
+        let claimed = CLAIMED.lock().unwrap();
+        assert!(second.ports().iter().all(|port| claimed.contains(port)));
+    }
+}
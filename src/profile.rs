@@ -0,0 +1,364 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use rand::Rng;
+
+/// Every operation that a step may perform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Op {
+    Restart,
+    Shutdown,
+    LameDuck,
+    Amnesia,
+    Corrupt,
+    Rollback,
+    Pause,
+    Resume,
+    Partition,
+    Heal,
+    Degrade,
+    Restore,
+    Publish,
+    Consume,
+}
+
+impl Op {
+    pub const ALL: [Op; 14] = [
+        Op::Restart,
+        Op::Shutdown,
+        Op::LameDuck,
+        Op::Amnesia,
+        Op::Corrupt,
+        Op::Rollback,
+        Op::Pause,
+        Op::Resume,
+        Op::Partition,
+        Op::Heal,
+        Op::Degrade,
+        Op::Restore,
+        Op::Publish,
+        Op::Consume,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Op::Restart => "restart",
+            Op::Shutdown => "shutdown",
+            Op::LameDuck => "lame-duck",
+            Op::Amnesia => "amnesia",
+            Op::Corrupt => "corrupt",
+            Op::Rollback => "rollback",
+            Op::Pause => "pause",
+            Op::Resume => "resume",
+            Op::Partition => "partition",
+            Op::Heal => "heal",
+            Op::Degrade => "degrade",
+            Op::Restore => "restore",
+            Op::Publish => "publish",
+            Op::Consume => "consume",
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Op {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Op, String> {
+        Op::ALL
+            .iter()
+            .copied()
+            .find(|op| op.name() == s)
+            .ok_or_else(|| format!("unknown operation {:?}", s))
+    }
+}
+
+/// The highest weight an op may have, so that the total of
+/// every weight can't overflow.
+pub const MAX_WEIGHT: u64 = 1_000_000_000;
+
+/// The relative likelihood of each `Op` being chosen
+/// by a step. An op with a weight of 0 is never chosen.
+#[derive(Clone, PartialEq)]
+pub struct Profile {
+    // indexed by position in `Op::ALL`
+    weights: [u64; Op::ALL.len()],
+}
+
+// name, then weights in the same order as `Op::ALL`
+#[rustfmt::skip]
+const BUILT_IN: &[(&str, [u64; Op::ALL.len()])] = &[
+    //                   restart  shutdown  lame-duck  amnesia  corrupt  rollback  pause  resume  partition  heal  degrade  restore  publish  consume
+    ("default",         [      6,        3,         2,       2,       2,        2,    35,     50,         5,   15,       5,      15,      70,     788]),
+    ("restart-heavy",   [    100,       40,        20,      20,      10,       10,    20,     40,         5,   15,       5,      15,     100,     600]),
+    ("pause-storm",     [      2,        1,         1,       1,       5,        5,   200,    150,         5,   15,       5,      15,      70,     525]),
+    ("partition-heavy", [      6,        3,         2,       2,       2,        2,    20,     30,        80,   60,      20,      40,      70,     663]),
+    ("read-mostly",     [      6,        3,         2,       2,       2,        2,    35,     50,         5,   15,       5,      15,      10,     848]),
+];
+
+impl Profile {
+    /// Returns one of the built-in profiles.
+    pub fn named(name: &str) -> Option<Profile> {
+        BUILT_IN
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, weights)| Profile { weights: *weights })
+    }
+
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        BUILT_IN.iter().map(|(name, _)| *name)
+    }
+
+    /// Builds a profile where only the given ops may be chosen.
+    pub fn from_weights(weights: &[(Op, u64)]) -> Profile {
+        let mut profile = Profile {
+            weights: [0; Op::ALL.len()],
+        };
+        for (op, weight) in weights {
+            profile.set(*op, *weight);
+        }
+        profile
+    }
+
+    /// Loads a profile file, which has one `op = weight`
+    /// line per op. Ops that are not listed are never chosen,
+    /// and lines starting with `#` are ignored.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Profile> {
+        let content = std::fs::read_to_string(path)?;
+        let mut profile = Profile::from_weights(&[]);
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid profile line {:?}: {}", line, e),
+                )
+            };
+
+            let mut splits = line.splitn(2, '=').map(str::trim);
+            let op: Op = splits.next().unwrap().parse().map_err(invalid)?;
+            let weight = splits
+                .next()
+                .ok_or_else(|| "missing weight".to_string())
+                .and_then(|w| w.parse::<u64>().map_err(|e| e.to_string()))
+                .and_then(check_weight)
+                .map_err(invalid)?;
+
+            profile.set(op, weight);
+        }
+
+        Ok(profile)
+    }
+
+    pub fn weight(&self, op: Op) -> u64 {
+        self.weights[index(op)]
+    }
+
+    pub fn set(&mut self, op: Op, weight: u64) {
+        self.weights[index(op)] = weight;
+    }
+
+    pub fn scale(&mut self, op: Op, factor: f64) {
+        let weight = self.weight(op) as f64 * factor;
+        self.set(op, weight.round() as u64);
+    }
+
+    /// Disables every op that is not in `ops`.
+    pub fn restrict(&mut self, ops: &[Op]) {
+        for op in &Op::ALL {
+            if !ops.contains(op) {
+                self.set(*op, 0);
+            }
+        }
+    }
+
+    /// Applies overrides like `restart:0,pause:x2.5`, where
+    /// `op:N` sets a weight and `op:xN` scales it.
+    pub fn apply_overrides(&mut self, overrides: &str) -> Result<(), String> {
+        for spec in overrides.split(',').filter(|s| !s.is_empty()) {
+            let mut splits = spec.splitn(2, ':');
+            let op: Op = splits.next().unwrap().parse()?;
+            let value = splits
+                .next()
+                .ok_or_else(|| format!("missing weight for {} in {:?}", op, spec))?;
+
+            if let Some(factor) = value.strip_prefix('x') {
+                let factor = factor
+                    .parse()
+                    .map_err(|e| format!("invalid factor in {:?}: {}", spec, e))?;
+                self.scale(op, factor);
+                check_weight(self.weight(op))
+                    .map_err(|e| format!("invalid factor in {:?}: {}", spec, e))?;
+            } else {
+                let weight = value
+                    .parse()
+                    .map_err(|e| format!("invalid weight in {:?}: {}", spec, e))
+                    .and_then(|weight| {
+                        check_weight(weight)
+                            .map_err(|e| format!("invalid weight in {:?}: {}", spec, e))
+                    })?;
+                self.set(op, weight);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn total(&self) -> u64 {
+        self.weights.iter().sum()
+    }
+
+    /// Picks an op with probability proportional to its weight.
+    pub fn choose<R: Rng>(&self, rng: &mut R) -> Op {
+        assert!(
+            self.total() > 0,
+            "all operations in the profile are disabled"
+        );
+
+        let mut choice = rng.gen_range(0..self.total());
+        for (op, weight) in Op::ALL.iter().zip(self.weights.iter()) {
+            if choice < *weight {
+                return *op;
+            }
+            choice -= weight;
+        }
+        unreachable!("impossible choice")
+    }
+}
+
+fn check_weight(weight: u64) -> Result<u64, String> {
+    if weight > MAX_WEIGHT {
+        Err(format!(
+            "weight {} is above the maximum of {}",
+            weight, MAX_WEIGHT
+        ))
+    } else {
+        Ok(weight)
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Profile {
+        Profile::named("default").unwrap()
+    }
+}
+
+impl fmt::Debug for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(Op::ALL.iter().map(|op| (op.name(), self.weight(*op))))
+            .finish()
+    }
+}
+
+/// Parses either the name of a built-in profile or
+/// the path to a profile file.
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Profile, String> {
+        if let Some(profile) = Profile::named(s) {
+            return Ok(profile);
+        }
+        Profile::load(s).map_err(|e| {
+            format!(
+                "{:?} is neither a built-in profile ({}) nor a readable profile file: {}",
+                s,
+                Profile::names().collect::<Vec<_>>().join(", "),
+                e
+            )
+        })
+    }
+}
+
+fn index(op: Op) -> usize {
+    Op::ALL.iter().position(|o| *o == op).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_set_and_scale_weights() {
+        let mut profile = Profile::from_weights(&[(Op::Pause, 10), (Op::Consume, 5)]);
+        profile
+            .apply_overrides("pause:x2.5,consume:0,heal:3")
+            .unwrap();
+
+        assert_eq!(profile.weight(Op::Pause), 25);
+        assert_eq!(profile.weight(Op::Consume), 0);
+        assert_eq!(profile.weight(Op::Heal), 3);
+    }
+
+    #[test]
+    fn bad_overrides_are_rejected() {
+        for overrides in &[
+            "pause:xfast",
+            "pause:x",
+            "pause:-1",
+            "pause",
+            "nope:1",
+            "pause:x1e30",
+            "pause:18446744073709551615",
+        ] {
+            let mut profile = Profile::default();
+            assert!(
+                profile.apply_overrides(overrides).is_err(),
+                "accepted {:?}",
+                overrides
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_ops_are_rejected() {
+        assert!("nope".parse::<Op>().is_err());
+        assert!("Pause".parse::<Op>().is_err());
+        assert_eq!("lame-duck".parse::<Op>(), Ok(Op::LameDuck));
+    }
+
+    #[test]
+    fn profile_files_only_enable_listed_ops() {
+        let path = std::env::temp_dir().join(format!("profile_{}.txt", std::process::id()));
+        std::fs::write(&path, "# comment\n\npause = 3\n consume=7 \n").unwrap();
+        let profile = Profile::load(&path).unwrap();
+
+        assert_eq!(
+            profile,
+            Profile::from_weights(&[(Op::Pause, 3), (Op::Consume, 7)])
+        );
+
+        for content in &[
+            "pause 3\n",
+            "pause = x2\n",
+            "nope = 1\n",
+            "pause = 1000000001\n",
+        ] {
+            std::fs::write(&path, content).unwrap();
+            let err = Profile::load(&path).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", content);
+        }
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn profiles_parse_from_names_or_files() {
+        assert_eq!(
+            "pause-storm".parse(),
+            Ok(Profile::named("pause-storm").unwrap())
+        );
+        assert!("no-such-profile".parse::<Profile>().is_err());
+    }
+}
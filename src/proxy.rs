@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering::SeqCst};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rand::Rng;
+
+/// A local TCP proxy that forwards every accepted
+/// connection to an upstream port on localhost. Cutting
+/// the proxy tears down all live connections and refuses
+/// new ones until it is healed, which lets us partition
+/// servers from each other while they keep running.
+/// Degrading the proxy slows down and occasionally resets
+/// the traffic that flows through it.
+pub(crate) struct Proxy {
+    port: u16,
+    shared: Arc<Shared>,
+}
+
+/// How traffic on a degraded link is mangled. Delay,
+/// jitter and bandwidth limits are applied to every chunk
+/// that is forwarded in either direction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Degradation {
+    pub delay: Duration,
+    pub jitter: Duration,
+    // bytes per second, unlimited if `None`
+    pub bandwidth: Option<u64>,
+    // chance of resetting the connection per forwarded chunk
+    pub reset_probability: f64,
+}
+
+struct Shared {
+    upstream: u16,
+    degradation: Mutex<Option<Degradation>>,
+    cut: AtomicBool,
+    shutdown: AtomicBool,
+    conn_ids: AtomicU64,
+    conns: Mutex<HashMap<u64, (TcpStream, TcpStream)>>,
+}
+
+impl Proxy {
+    pub(crate) fn start(upstream: u16) -> Proxy {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("unable to bind proxy listener");
+        let port = listener.local_addr().unwrap().port();
+
+        let shared = Arc::new(Shared {
+            upstream,
+            degradation: Mutex::new(None),
+            cut: AtomicBool::new(false),
+            shutdown: AtomicBool::new(false),
+            conn_ids: AtomicU64::new(0),
+            conns: Default::default(),
+        });
+
+        let accept_shared = shared.clone();
+        std::thread::spawn(move || accept_loop(listener, accept_shared));
+
+        Proxy { port, shared }
+    }
+
+    pub(crate) fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub(crate) fn is_cut(&self) -> bool {
+        self.shared.cut.load(SeqCst)
+    }
+
+    /// Drops every live connection and refuses new ones.
+    pub(crate) fn cut(&self) {
+        self.shared.cut.store(true, SeqCst);
+
+        let conns = std::mem::take(&mut *self.shared.conns.lock().unwrap());
+        for (_, (downstream, upstream)) in conns {
+            let _ = downstream.shutdown(Shutdown::Both);
+            let _ = upstream.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub(crate) fn heal(&self) {
+        self.shared.cut.store(false, SeqCst);
+    }
+
+    pub(crate) fn is_degraded(&self) -> bool {
+        self.shared.degradation.lock().unwrap().is_some()
+    }
+
+    pub(crate) fn degrade(&self, degradation: Degradation) {
+        *self.shared.degradation.lock().unwrap() = Some(degradation);
+    }
+
+    pub(crate) fn restore(&self) {
+        *self.shared.degradation.lock().unwrap() = None;
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, SeqCst);
+        self.cut();
+
+        // wake up the accept loop so it can notice the shutdown
+        let _ = TcpStream::connect(("127.0.0.1", self.port));
+    }
+}
+
+fn accept_loop(listener: TcpListener, shared: Arc<Shared>) {
+    for stream in listener.incoming() {
+        if shared.shutdown.load(SeqCst) {
+            return;
+        }
+
+        let downstream = if let Ok(stream) = stream {
+            stream
+        } else {
+            continue;
+        };
+
+        if shared.cut.load(SeqCst) {
+            // refuse connections while cut
+            let _ = downstream.shutdown(Shutdown::Both);
+            continue;
+        }
+
+        let upstream = if let Ok(upstream) = TcpStream::connect(("127.0.0.1", shared.upstream)) {
+            upstream
+        } else {
+            let _ = downstream.shutdown(Shutdown::Both);
+            continue;
+        };
+
+        let _ = downstream.set_nodelay(true);
+        let _ = upstream.set_nodelay(true);
+
+        if let Err(e) = forward(&shared, downstream, upstream) {
+            eprintln!(
+                "proxy to port {} failed to forward: {:?}",
+                shared.upstream, e
+            );
+        }
+    }
+}
+
+fn forward(shared: &Arc<Shared>, downstream: TcpStream, upstream: TcpStream) -> io::Result<()> {
+    let id = shared.conn_ids.fetch_add(1, SeqCst);
+
+    {
+        let mut conns = shared.conns.lock().unwrap();
+
+        // `cut` may have run since the accept loop checked it,
+        // in which case it already missed this connection
+        if shared.cut.load(SeqCst) {
+            let _ = downstream.shutdown(Shutdown::Both);
+            let _ = upstream.shutdown(Shutdown::Both);
+            return Ok(());
+        }
+
+        conns.insert(id, (downstream.try_clone()?, upstream.try_clone()?));
+    }
+
+    let (down_read, up_write) = (downstream.try_clone()?, upstream.try_clone()?);
+
+    let shared_1 = shared.clone();
+    std::thread::spawn(move || pipe(shared_1, id, down_read, up_write));
+
+    let shared_2 = shared.clone();
+    std::thread::spawn(move || pipe(shared_2, id, upstream, downstream));
+
+    Ok(())
+}
+
+fn pipe(shared: Arc<Shared>, id: u64, mut from: TcpStream, mut to: TcpStream) {
+    let mut buf = vec![0; 16 * 1024];
+
+    loop {
+        let n = match from.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+
+        let degradation = *shared.degradation.lock().unwrap();
+
+        if let Some(degradation) = degradation {
+            let mut rng = rand::thread_rng();
+
+            if rng.gen_bool(degradation.reset_probability) {
+                break;
+            }
+
+            let mut sleep = degradation.delay;
+            if degradation.jitter > Duration::from_millis(0) {
+                sleep += rng.gen_range(Duration::from_millis(0)..degradation.jitter);
+            }
+            if let Some(bandwidth) = degradation.bandwidth {
+                sleep += Duration::from_secs_f64(n as f64 / bandwidth.max(1) as f64);
+            }
+            std::thread::sleep(sleep);
+        }
+
+        if to.write_all(&buf[..n]).is_err() {
+            break;
+        }
+    }
+
+    // once either direction fails, tear down both sides
+    let _ = from.shutdown(Shutdown::Both);
+    let _ = to.shutdown(Shutdown::Both);
+
+    shared.conns.lock().unwrap().remove(&id);
+}
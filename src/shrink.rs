@@ -0,0 +1,152 @@
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::trace::{self, Decision, TraceWriter};
+use crate::{Args, Cluster, Violation};
+
+/// Delta-debugs the schedule recorded in the `--shrink`
+/// trace: keeps removing chunks of its steps for as long as
+/// replaying the rest still runs into the same kind of
+/// violation, found by the same check. The smallest schedule
+/// found so far is written next to the original trace after
+/// every reduction, and its path is returned once no single
+/// chunk can be removed.
+pub fn shrink(args: Args) -> io::Result<PathBuf> {
+    let path = args.shrink.clone().expect("shrinking requires --shrink");
+    let mut schedule = trace::load(&path)?.decisions;
+    let output = shrunk_path(&path);
+
+    println!(
+        "replaying all {} steps of {:?} to find the violation to preserve",
+        schedule.len(),
+        path
+    );
+
+    let signature = match reproduce(&args, &schedule, None) {
+        Some(signature) => signature,
+        None => {
+            return Err(io::Error::other(format!(
+                "replaying {:?} {} times did not run into a violation",
+                path, args.shrink_runs
+            )))
+        }
+    };
+
+    println!(
+        "shrinking a schedule that runs into a {} violation ({})",
+        signature.0, signature.1
+    );
+
+    let mut chunks = 2;
+    while schedule.len() >= 2 {
+        let chunk_len = schedule.len().div_ceil(chunks);
+        let mut reduced = false;
+
+        for start in (0..schedule.len()).step_by(chunk_len) {
+            let end = (start + chunk_len).min(schedule.len());
+            let candidate: Vec<Decision> = schedule[..start]
+                .iter()
+                .chain(&schedule[end..])
+                .cloned()
+                .collect();
+
+            println!(
+                "trying {} steps without steps {}..{} of {}",
+                candidate.len(),
+                start,
+                end,
+                schedule.len()
+            );
+
+            if reproduce(&args, &candidate, Some(signature)).is_some() {
+                schedule = candidate;
+                save(&output, &args.recorded, &schedule)?;
+                println!("shrunk to {} steps, saved to {:?}", schedule.len(), output);
+
+                chunks = (chunks - 1).max(2);
+                reduced = true;
+                break;
+            }
+        }
+
+        if !reduced {
+            if chunks >= schedule.len() {
+                // not even a single step can be removed
+                break;
+            }
+            chunks = (chunks * 2).min(schedule.len());
+        }
+    }
+
+    save(&output, &args.recorded, &schedule)?;
+    println!(
+        "smallest schedule that runs into the {} violation ({}) has {} steps: {:?}",
+        signature.0,
+        signature.1,
+        schedule.len(),
+        output
+    );
+
+    Ok(output)
+}
+
+/// The kind of a violation and the check that found it.
+type Signature = (&'static str, &'static str);
+
+/// Replays the schedule up to `--shrink-runs` times, returning
+/// the signature of the violation it ran into. If `signature`
+/// is given, only a violation with the same one counts.
+fn reproduce(
+    args: &Args,
+    schedule: &[Decision],
+    signature: Option<Signature>,
+) -> Option<Signature> {
+    for _ in 0..args.shrink_runs {
+        if let Some(violation) = replay(args, schedule) {
+            let found = (violation.kind, violation.check);
+            match signature {
+                Some(signature) if signature != found => {}
+                _ => return Some(found),
+            }
+        }
+    }
+    None
+}
+
+/// Runs the schedule on a fresh cluster, including the
+/// final drain, which is dropped again before returning.
+/// A replay that panics, like when the cluster doesn't
+/// start, counts as not running into a violation.
+fn replay(args: &Args, schedule: &[Decision]) -> Option<Violation> {
+    let mut args = args.clone();
+    args.start_time = Instant::now();
+    args.steps = schedule.len() as u64;
+    args.trace = None;
+    args.history = None;
+
+    let replayed = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut cluster = Cluster::start(args);
+        cluster.replay = Some(schedule.iter().cloned().collect());
+        cluster.run().err()
+    }));
+
+    replayed.unwrap_or_else(|_| {
+        println!("replay panicked, counting it as not reproduced");
+        None
+    })
+}
+
+fn save(path: &Path, args: &[String], schedule: &[Decision]) -> io::Result<()> {
+    let mut writer = TraceWriter::create(path, args)?;
+    for decision in schedule {
+        writer.record(decision);
+    }
+    Ok(())
+}
+
+fn shrunk_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.shrunk.trace", stem))
+}
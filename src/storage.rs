@@ -0,0 +1,137 @@
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+// the files that hold the state of a stream and its consumers
+// besides the messages themselves: per-block indexes before
+// nats-server 2.10, the stream's index since, and the state
+// of each consumer
+const STATE_FILES: &[&str] = &["*.idx", "index.db", "o.dat"];
+
+/// Damages the storage directory of a stopped server,
+/// returning a description of what was done. Prefers the
+/// message blocks and state files of the test stream, and
+/// falls back to deleting an arbitrary file.
+pub(crate) fn corrupt<R: Rng>(storage_dir: &Path, rng: &mut R) -> io::Result<String> {
+    let files = files(storage_dir)?;
+
+    let stream_files = |names: &[&str]| -> Vec<&PathBuf> {
+        files
+            .iter()
+            .filter(|path| path.to_string_lossy().contains("exercise_stream/"))
+            .filter(|path| names.iter().any(|name| matches_name(path, name)))
+            .collect()
+    };
+
+    let blocks = stream_files(&["*.blk"]);
+    let state_files = stream_files(STATE_FILES);
+
+    match rng.gen_range(0..3) {
+        0 if !blocks.is_empty() => {
+            // message blocks are numbered, so the newest has the highest number
+            let newest = blocks.iter().max_by_key(|path| block_number(path)).unwrap();
+            let len = fs::metadata(newest)?.len();
+            if len == 0 {
+                return Ok(format!("left empty message block {:?} alone", newest));
+            }
+            let new_len = len - rng.gen_range(1..=len.min(4096));
+
+            OpenOptions::new()
+                .write(true)
+                .open(newest)?
+                .set_len(new_len)?;
+
+            Ok(format!(
+                "truncated message block {:?} from {} to {} bytes",
+                newest, len, new_len
+            ))
+        }
+        1 if !state_files.is_empty() => {
+            let file = state_files.choose(rng).unwrap();
+            let mut data = fs::read(file)?;
+            if data.is_empty() {
+                return Ok(format!("left empty state file {:?} alone", file));
+            }
+
+            let flips = rng.gen_range(1..=8);
+            for _ in 0..flips {
+                let byte = rng.gen_range(0..data.len());
+                data[byte] ^= 1 << rng.gen_range(0..8);
+            }
+
+            fs::write(file, data)?;
+
+            Ok(format!("flipped {} bits in state file {:?}", flips, file))
+        }
+        _ => {
+            if let Some(file) = files.choose(rng) {
+                fs::remove_file(file)?;
+                Ok(format!("deleted {:?}", file))
+            } else {
+                Ok("found no files to damage".into())
+            }
+        }
+    }
+}
+
+/// Replaces `to` with a copy of `from`.
+pub(crate) fn copy_dir<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> io::Result<()> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+
+    if to.exists() {
+        fs::remove_dir_all(to)?;
+    }
+    fs::create_dir_all(to)?;
+
+    for path in files(from)? {
+        let dest = to.join(path.strip_prefix(from).unwrap());
+        fs::create_dir_all(dest.parent().unwrap())?;
+        fs::copy(&path, dest)?;
+    }
+
+    Ok(())
+}
+
+/// Whether the file is called `name`, or has its extension
+/// if `name` is like `*.ext`.
+fn matches_name(path: &Path, name: &str) -> bool {
+    match name.strip_prefix("*.") {
+        Some(extension) => path.extension().is_some_and(|e| e == extension),
+        None => path.file_name().is_some_and(|n| n == name),
+    }
+}
+
+fn block_number(path: &Path) -> u64 {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Every file below `dir`, in a stable order so that
+/// seeded choices among them can be replayed.
+fn files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        if !dir.exists() {
+            continue;
+        }
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+
+    files.sort();
+
+    Ok(files)
+}
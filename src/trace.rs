@@ -0,0 +1,129 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// One step of a schedule, with every choice it made, so
+/// that replaying it doesn't depend on the rng or timing.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub(crate) enum Decision {
+    Restart {
+        server: usize,
+    },
+    Shutdown {
+        server: usize,
+    },
+    LameDuck {
+        server: usize,
+    },
+    Amnesia {
+        server: usize,
+    },
+    Corrupt {
+        server: usize,
+        /// seeds the choice of what to damage
+        seed: u64,
+    },
+    Rollback {
+        server: usize,
+    },
+    Pause {
+        server: usize,
+    },
+    Resume {
+        server: usize,
+    },
+    Partition {
+        shape: String,
+        cut: Vec<(usize, usize)>,
+    },
+    Heal,
+    Degrade {
+        link: Link,
+    },
+    Restore,
+    /// also recorded for the probes of availability checks
+    Publish {
+        client: usize,
+    },
+    Consume {
+        client: usize,
+    },
+}
+
+/// A link that can be degraded.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Link {
+    /// the link that clients of a server connect through
+    Client(usize),
+    /// both directions of the route between two servers
+    Route(usize, usize),
+}
+
+// the first line of a trace
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    args: Vec<String>,
+}
+
+/// A recorded schedule, along with the options of the run
+/// that recorded it.
+#[derive(Debug)]
+pub(crate) struct Trace {
+    pub args: Vec<String>,
+    pub decisions: Vec<Decision>,
+}
+
+/// Records the decisions of a run to a newline-delimited
+/// JSON file, flushing each one right away so the trace
+/// survives the process exiting on a violation.
+pub(crate) struct TraceWriter {
+    out: LineWriter<File>,
+}
+
+impl TraceWriter {
+    pub(crate) fn create<P: AsRef<Path>>(path: P, args: &[String]) -> io::Result<TraceWriter> {
+        let mut writer = TraceWriter {
+            out: LineWriter::new(File::create(path)?),
+        };
+        writer.write(&Header {
+            args: args.to_vec(),
+        })?;
+        Ok(writer)
+    }
+
+    pub(crate) fn record(&mut self, decision: &Decision) {
+        self.write(decision).expect("unable to write trace");
+    }
+
+    fn write<T: Serialize>(&mut self, line: &T) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, line)?;
+        self.out.write_all(b"\n")
+    }
+}
+
+pub(crate) fn load<P: AsRef<Path>>(path: P) -> io::Result<Trace> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut lines = reader.lines();
+
+    let header: Header = match lines.next() {
+        Some(line) => serde_json::from_str(&line?)?,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "trace is empty")),
+    };
+
+    let mut decisions = vec![];
+    for line in lines {
+        let line = line?;
+        if !line.trim().is_empty() {
+            decisions.push(serde_json::from_str(&line)?);
+        }
+    }
+
+    Ok(Trace {
+        args: header.args,
+        decisions,
+    })
+}